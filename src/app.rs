@@ -1,14 +1,28 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, MutexGuard};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::spawn_local;
+use web_sys::HtmlCanvasElement;
 
+use crate::canvas::{Canvas, LineCap};
 use crate::proxy::Proxy;
+use crate::simulation::SpawnMask;
+use crate::utils::{
+    canvas_to_screen, hex_to_rgb, is_transparent,
+    screen_to_canvas,
+};
 
 #[wasm_bindgen]
 pub struct App {
     proxy: Arc<Mutex<Proxy>>,
+    // Shared with 'proxy' so that we can read
+    // them without locking it.
+    running: Rc<Cell<bool>>,
+    paused: Rc<Cell<bool>>,
+    destroyed: Rc<Cell<bool>>,
 }
 
 #[wasm_bindgen]
@@ -17,20 +31,643 @@ impl App {
     pub fn new(
         params: &JsValue,
     ) -> Result<App, JsValue> {
-        Ok(App {
-            proxy: Arc::new(Mutex::new(Proxy::new(
-                params,
-            ))),
-        })
+        Ok(App::with_proxy(Proxy::new(params)?))
     }
 
+    // For frameworks which hold the element
+    // (ex. refs), instead of finding it
+    // by 'selector'.
     #[wasm_bindgen]
-    pub fn start(&mut self) {
-        let proxy = Arc::clone(&self.proxy);
+    pub fn from_canvas(
+        canvas: HtmlCanvasElement,
+        params: &JsValue,
+    ) -> Result<App, JsValue> {
+        Ok(App::with_proxy(Proxy::from_canvas(
+            canvas, params,
+        )?))
+    }
+
+    // Either use 'start' to run the built-in loop,
+    // or call 'tick' from your own loop, not both.
+    #[wasm_bindgen]
+    pub fn start(&mut self) -> Result<(), JsValue> {
+        let proxy = self.proxy()?;
+        if proxy.running.get() {
+            return Err("Already running".into());
+        }
+        proxy.running.set(true);
+
+        // Running the loop on a clone so that
+        // we don't hold the lock forever.
+        // The clone shares the same canvas.
+        let mut proxy = proxy.clone();
         spawn_local(async move {
-            let mut proxy = proxy.lock().await;
             proxy.run().await;
-            drop(proxy); // release the lock before the await point
         });
+        Ok(())
+    }
+
+    // Updates and draws exactly once.
+    #[wasm_bindgen]
+    pub fn tick(&mut self) -> Result<(), JsValue> {
+        let proxy = self.proxy()?;
+        if proxy.running.get() {
+            return Err(
+                "Cannot tick while the loop is running"
+                    .into(),
+            );
+        }
+        proxy.tick();
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn set_colors(
+        &mut self,
+        bgcolor: String,
+        color: String,
+    ) -> Result<(), JsValue> {
+        if !is_transparent(&bgcolor) {
+            hex_to_rgb(&bgcolor)?;
+        }
+        hex_to_rgb(&color)?;
+        self.canvas()?
+            .borrow_mut()
+            .set_colors(bgcolor, color);
+        Ok(())
+    }
+
+    // Eases into the colors over 'frames'.
+    #[wasm_bindgen]
+    pub fn transition_colors(
+        &mut self,
+        to_bg: String,
+        to_color: String,
+        frames: u32,
+    ) -> Result<(), JsValue> {
+        if !is_transparent(&to_bg) {
+            hex_to_rgb(&to_bg)?;
+        }
+        hex_to_rgb(&to_color)?;
+        self.canvas()?
+            .borrow_mut()
+            .transition_colors(
+                to_bg, to_color, frames,
+            );
+        Ok(())
+    }
+
+    // Whether the loop of 'start' is active.
+    // Cheap enough to poll every frame.
+    #[wasm_bindgen]
+    pub fn is_running(&self) -> bool {
+        self.running.get() && !self.destroyed.get()
+    }
+
+    #[wasm_bindgen]
+    pub fn is_paused(&self) -> bool {
+        self.paused.get()
+    }
+
+    #[wasm_bindgen]
+    pub fn pause(&mut self) -> Result<(), JsValue> {
+        self.proxy()?.pause();
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn resume(&mut self) -> Result<(), JsValue> {
+        self.proxy()?.resume();
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn destroy(&mut self) -> Result<(), JsValue> {
+        self.proxy()?.destroy();
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn set_speed(
+        &mut self,
+        speed: f64,
+    ) -> Result<(), JsValue> {
+        self.canvas()?.borrow_mut().set_speed(speed);
+        Ok(())
+    }
+
+    // Eases into the speed over 'frames'.
+    #[wasm_bindgen]
+    pub fn ramp_speed(
+        &mut self,
+        target: f64,
+        frames: u32,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .ramp_speed(target, frames);
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn set_ripple_range_units(
+        &mut self,
+        units: f64,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .set_ripple_range_units(units);
+        Ok(())
+    }
+
+    // In CSS pixels.
+    #[wasm_bindgen]
+    pub fn set_stick_width(
+        &mut self,
+        width: f64,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .set_stick_width(width);
+        Ok(())
+    }
+
+    // "butt", "round", or "square"
+    #[wasm_bindgen]
+    pub fn set_stick_cap(
+        &mut self,
+        cap: JsValue,
+    ) -> Result<(), JsValue> {
+        let cap: LineCap =
+            serde_wasm_bindgen::from_value(cap)?;
+        self.canvas()?.borrow_mut().stick_cap = cap;
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn set_particle_size(
+        &mut self,
+        size: f64,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .set_particle_size(size);
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn set_draw_sticks(
+        &mut self,
+        on: bool,
+    ) -> Result<(), JsValue> {
+        self.canvas()?.borrow_mut().draw_sticks = on;
+        Ok(())
+    }
+
+    // Particles stop moving, but sticks keep
+    // following the flow.
+    #[wasm_bindgen]
+    pub fn set_freeze_particles(
+        &mut self,
+        on: bool,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .sim
+            .freeze_particles = on;
+        Ok(())
+    }
+
+    // The flow stops changing, and particles
+    // keep going in their last directions.
+    #[wasm_bindgen]
+    pub fn set_freeze_field(
+        &mut self,
+        on: bool,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .sim
+            .freeze_field = on;
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn set_draw_particles(
+        &mut self,
+        on: bool,
+    ) -> Result<(), JsValue> {
+        self.canvas()?.borrow_mut().draw_particles =
+            on;
+        Ok(())
+    }
+
+    // Unlike changing 'num_of_particles'
+    // (which needs a new 'App'), the rest of
+    // particles keep moving as they are.
+    #[wasm_bindgen]
+    pub fn add_particles(
+        &mut self,
+        n: usize,
+    ) -> Result<(), JsValue> {
+        self.canvas()?.borrow_mut().add_particles(n);
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn remove_particles(
+        &mut self,
+        n: usize,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .remove_particles(n);
+        Ok(())
+    }
+
+    // Shuffles particles (or puts them back
+    // where they were if 'seed' is given).
+    #[wasm_bindgen]
+    pub fn reset(&mut self) -> Result<(), JsValue> {
+        self.canvas()?.borrow_mut().reset();
+        Ok(())
+    }
+
+    // Particles are generated again with
+    // the density following 'data' (grayscale,
+    // 'w' x 'h', row by row), which is
+    // stretched over the canvas.
+    #[wasm_bindgen]
+    pub fn set_mask(
+        &mut self,
+        data: Vec<u8>,
+        w: usize,
+        h: usize,
+    ) -> Result<(), JsValue> {
+        // 'w' x 'h' could overflow on wasm32.
+        if w == 0
+            || h == 0
+            || w.checked_mul(h) != Some(data.len())
+        {
+            return Err(format!(
+                "Mask should have {} x {} bytes \
+                 (got {})",
+                w,
+                h,
+                data.len()
+            )
+            .into());
+        }
+        let canvas = self.canvas()?;
+        let mut canvas = canvas.borrow_mut();
+        canvas.sim.spawn_mask = Some(SpawnMask {
+            data,
+            width: w,
+            height: h,
+        });
+        canvas.reset();
+        Ok(())
+    }
+
+    // Back to 'spawn_shape'.
+    #[wasm_bindgen]
+    pub fn clear_mask(
+        &mut self,
+    ) -> Result<(), JsValue> {
+        let canvas = self.canvas()?;
+        let mut canvas = canvas.borrow_mut();
+        canvas.sim.spawn_mask = None;
+        canvas.reset();
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn set_debug_grid(
+        &mut self,
+        on: bool,
+    ) -> Result<(), JsValue> {
+        self.canvas()?.borrow_mut().debug_grid = on;
+        Ok(())
+    }
+
+    // Arrows showing the flow at grid points.
+    #[wasm_bindgen]
+    pub fn set_field_debug(
+        &mut self,
+        on: bool,
+    ) -> Result<(), JsValue> {
+        self.canvas()?.borrow_mut().field_debug = on;
+        Ok(())
+    }
+
+    // 'undefined' unless 'seed' was given
+    // (or set with 'set_seed').
+    #[wasm_bindgen]
+    pub fn get_seed(&self) -> Option<u32> {
+        self.canvas().ok()?.borrow().sim.seed
+    }
+
+    // Only the flow field changes, unless
+    // 'regenerate' is given.
+    #[wasm_bindgen]
+    pub fn set_seed(
+        &mut self,
+        seed: u32,
+        regenerate: bool,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .sim
+            .set_seed(seed, regenerate);
+        Ok(())
+    }
+
+    // For scrubbing through the flow.
+    // Particles continue from where they are.
+    // 1.0 is 'time_divisor' (100 by default)
+    // frames at 'time_scale' 1.0.
+    #[wasm_bindgen]
+    pub fn set_noise_time(
+        &mut self,
+        t: f64,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .sim
+            .set_noise_time(t);
+        Ok(())
+    }
+
+    // Negative 'delta' to go backward.
+    #[wasm_bindgen]
+    pub fn step_noise_time(
+        &mut self,
+        delta: f64,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .sim
+            .step_noise_time(delta);
+        Ok(())
+    }
+
+    // Sizes in CSS pixels.
+    #[wasm_bindgen]
+    pub fn resize(
+        &mut self,
+        width: f64,
+        height: f64,
+    ) -> Result<(), JsValue> {
+        if !width.is_finite()
+            || !height.is_finite()
+            || width < 0.0
+            || height < 0.0
+        {
+            return Err(format!(
+                "Invalid size: {} x {}",
+                width, height
+            )
+            .into());
+        }
+        self.canvas()?
+            .borrow_mut()
+            .resize(width, height);
+        Ok(())
+    }
+
+    // Msec to wait (at least) between frames.
+    // 0 for as fast as the display allows.
+    #[wasm_bindgen]
+    pub fn set_refresh_rate(
+        &mut self,
+        ms: u32,
+    ) -> Result<(), JsValue> {
+        self.proxy()?.refresh_rate_ms.set(ms);
+        Ok(())
+    }
+
+    // 'callback(frame, fps)' is called after
+    // each frame while running (not for 'tick').
+    // Replaces the previous one.
+    #[wasm_bindgen]
+    pub fn on_frame(
+        &mut self,
+        callback: js_sys::Function,
+    ) -> Result<(), JsValue> {
+        self.proxy()?
+            .on_frame
+            .replace(Some(callback));
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_on_frame(
+        &mut self,
+    ) -> Result<(), JsValue> {
+        self.proxy()?.on_frame.replace(None);
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn fps(&self) -> f64 {
+        self.proxy().map_or(0.0, |proxy| proxy.fps())
+    }
+
+    // Particles are in device pixels, so CSS pixels
+    // (ex. mouse positions) need to be converted.
+    // Returns [x, y].
+    #[wasm_bindgen]
+    pub fn screen_to_canvas(
+        &self,
+        x: f64,
+        y: f64,
+    ) -> Result<Vec<f64>, JsValue> {
+        let dpr = self.canvas()?.borrow().dpr;
+        let (x, y) = screen_to_canvas(x, y, dpr);
+        Ok(vec![x, y])
+    }
+
+    #[wasm_bindgen]
+    pub fn canvas_to_screen(
+        &self,
+        x: f64,
+        y: f64,
+    ) -> Result<Vec<f64>, JsValue> {
+        let dpr = self.canvas()?.borrow().dpr;
+        let (x, y) = canvas_to_screen(x, y, dpr);
+        Ok(vec![x, y])
+    }
+
+    // (1, 0) drifts particles to the right
+    // as fast as the flow moves them.
+    #[wasm_bindgen]
+    pub fn set_wind(
+        &mut self,
+        x: f64,
+        y: f64,
+    ) -> Result<(), JsValue> {
+        self.canvas()?.borrow_mut().sim.wind = (x, y);
+        Ok(())
+    }
+
+    // Pans (in canvas coordinates) and zooms
+    // the flow field without moving particles
+    // nor the element. (0, 0, 1) to reset.
+    #[wasm_bindgen]
+    pub fn set_view(
+        &mut self,
+        pan_x: f64,
+        pan_y: f64,
+        zoom: f64,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .sim
+            .set_view((pan_x, pan_y), zoom);
+        Ok(())
+    }
+
+    // (x, y) in canvas coordinates.
+    // Negative 'strength' repels particles.
+    #[wasm_bindgen]
+    pub fn set_attractor(
+        &mut self,
+        x: f64,
+        y: f64,
+        strength: f64,
+    ) -> Result<(), JsValue> {
+        self.canvas()?.borrow_mut().sim.attractor =
+            Some((x, y, strength));
+        Ok(())
+    }
+
+    // Pushes particles away from (x, y) once
+    // (ex. on clicks). In canvas coordinates.
+    #[wasm_bindgen]
+    pub fn impulse(
+        &mut self,
+        x: f64,
+        y: f64,
+        strength: f64,
+    ) -> Result<(), JsValue> {
+        self.canvas()?
+            .borrow_mut()
+            .sim
+            .impulse(x, y, strength);
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_attractor(
+        &mut self,
+    ) -> Result<(), JsValue> {
+        self.canvas()?.borrow_mut().sim.attractor =
+            None;
+        Ok(())
+    }
+
+    // (x, y) in canvas coordinates.
+    // Returns 'undefined' when there are no
+    // particles, or when the canvas is busy.
+    #[wasm_bindgen]
+    pub fn nearest_particle(
+        &self,
+        x: f64,
+        y: f64,
+    ) -> Option<JsValue> {
+        let canvas = self.canvas().ok()?;
+        let canvas = canvas.try_borrow().ok()?;
+        let particle =
+            canvas.nearest_particle(x, y)?;
+        serde_wasm_bindgen::to_value(particle).ok()
+    }
+
+    // Direction (radians) of the flow at (x, y)
+    // in canvas coordinates, the same as what
+    // particles there would get (without
+    // 'jitter'). Ex. to rotate a cursor along
+    // the flow. 'undefined' when the canvas
+    // is busy.
+    #[wasm_bindgen]
+    pub fn flow_angle_at(
+        &self,
+        x: f64,
+        y: f64,
+    ) -> Option<f64> {
+        let canvas = self.canvas().ok()?;
+        let canvas = canvas.try_borrow().ok()?;
+        Some(canvas.sim.field_angle(x, y))
+    }
+
+    // PNG of whatever is currently drawn
+    // (you may want to 'tick' first).
+    #[wasm_bindgen]
+    pub fn to_data_url(
+        &self,
+    ) -> Result<String, JsValue> {
+        self.canvas()?
+            .borrow()
+            .el
+            .to_data_url_with_type("image/png")
+    }
+
+    // Current options. Pass it to the constructor
+    // to set up the same.
+    #[wasm_bindgen]
+    pub fn get_config(
+        &self,
+    ) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(
+            &self.proxy()?.config(),
+        )?)
+    }
+
+    #[wasm_bindgen]
+    pub fn metrics(
+        &self,
+    ) -> Result<JsValue, JsValue> {
+        let canvas = self.canvas()?;
+        let canvas = canvas.borrow();
+        Ok(serde_wasm_bindgen::to_value(
+            &canvas.metrics(),
+        )?)
+    }
+
+    #[wasm_bindgen]
+    pub fn snapshot(
+        &self,
+    ) -> Result<JsValue, JsValue> {
+        let canvas = self.canvas()?;
+        let canvas = canvas.borrow();
+        Ok(serde_wasm_bindgen::to_value(
+            &canvas.snapshot(),
+        )?)
+    }
+}
+
+impl App {
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn with_proxy(proxy: Proxy) -> App {
+        App {
+            running: proxy.running.clone(),
+            paused: proxy.paused.clone(),
+            destroyed: proxy.destroyed.clone(),
+            proxy: Arc::new(Mutex::new(proxy)),
+        }
+    }
+
+    fn proxy(
+        &self,
+    ) -> Result<MutexGuard<'_, Proxy>, JsValue> {
+        Ok(self
+            .proxy
+            .try_lock()
+            .map_err(|_| "Proxy is busy")?)
+    }
+
+    fn canvas(
+        &self,
+    ) -> Result<Rc<RefCell<Canvas>>, JsValue> {
+        Ok(Rc::clone(&self.proxy()?.canvas))
     }
 }
@@ -7,9 +7,6 @@
 /// are fixed, angles are taken from
 /// the closest particles.
 use lerp::Lerp;
-use noise::{NoiseFn, Perlin};
-use rand::distributions::Uniform;
-use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::f64::consts::PI;
@@ -30,16 +27,69 @@ use web_sys::{
 // use kdtree::distance::squared_euclidean;
 // use kdtree::KdTree;
 
+use crate::grid::SpatialGrid;
+use crate::simulation::{
+    EdgeBehavior, FlowMode, Particle, Simulation,
+    SpawnShape, JITTER, SPEED, TIME_DIVISOR,
+};
 use crate::utils::{
-    color_change_intensity_hex, debounce,
-    device_pixel_ratio, get_canvas_size, get_ctx,
-    get_window, lazy_round,
+    angle_diff, color_change_intensity_hex,
+    create_canvas, debounce, device_pixel_ratio,
+    distance, ease_in_out_quad, get_canvas_size,
+    get_ctx, get_window, hex_to_rgb,
+    hex_to_rgba_string, is_transparent, lazy_round,
+    lerp, lerp_angle, lerp_hex, lerp_rgb, norm,
+    rgb_to_hex, toroidal_delta, toroidal_distance,
+    DomError, Easing,
 };
 
 const NUM_OF_PARTICLES: usize = 150;
+
+// Generating (and drawing) too many particles
+// would lock up the main thread, so we clamp
+// whatever is given from JS.
+const MAX_NUM_OF_PARTICLES: usize = 10000;
+
 const SECOND_COLOR_INTENSITY: f64 = 0.5;
 
-const SPEED: f64 = 3.0;
+// How far (in units) sticks "feel" particles.
+const RIPPLE_RANGE_UNITS: f64 = 8.0;
+const MIN_RIPPLE_RANGE_UNITS: f64 = 0.1;
+
+// In pixels.
+const STICK_MIN_LENGTH: f64 = 2.0;
+
+// Number of the closest particles
+// which stick angles are taken from.
+const STICK_NEIGHBORS: usize = 2;
+
+// For 'velocity_coloring', the number of colors
+// we prepare between 'color' and 'color2'.
+const VELOCITY_COLOR_STEPS: usize = 16;
+
+// For 'debug_grid'. Relative to 'color'.
+const DEBUG_GRID_INTENSITY: f64 = 0.3;
+
+// For 'field_debug'. Relative to 'unit_size'.
+const FIELD_ARROW_LENGTH: f64 = 0.6;
+const FIELD_ARROW_HEAD: f64 = 0.2;
+
+// For 'StickMode::Density', the number of
+// particles around for sticks to be the longest.
+const DENSITY_SATURATION: f64 = 5.0;
+
+// For 'resolution_scale'.
+const MIN_RESOLUTION_SCALE: f64 = 0.1;
+
+// For 'curved_sticks'. Not to curl
+// into hooks where the flow turns sharply.
+const MAX_STICK_BEND: f64 = PI / 2.0;
+
+// Canvas narrower than this (in CSS pixels)
+// is considered to be mobile.
+const MOBILE_BREAKPOINT: f64 = 768.0;
+
+const MIN_PARTICLE_SIZE: f64 = 0.5;
 
 const PARTICLE_SIZE_MOBILE: f64 = 6.5;
 const PARTICLE_SIZE_DESKTOP: f64 = 3.5;
@@ -51,11 +101,212 @@ const PARTICLE_SIZE_DESKTOP: f64 = 3.5;
 const GRID_SIZE_MOBILE: f64 = 15.0;
 const GRID_SIZE_DESKTOP: f64 = 50.0;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Particle {
-    x: f64,
-    y: f64,
-    angle: f64,
+// Current state of particles
+// which we pass to JS. Each particle has
+// its flow 'angle', and its displacement
+// in the last frame as 'vx' and 'vy'.
+#[derive(Serialize, Debug)]
+pub struct Snapshot<'a> {
+    frame: i32,
+    width: f64,
+    height: f64,
+    angle_turns: f64,
+    particles: &'a [Particle],
+}
+
+// For 'Canvas.transition_colors'.
+// Colors are eased from 'from_*' to 'to_*'
+// as 'progress' goes up to 'frames'.
+#[derive(Debug, Clone)]
+pub struct ColorTransition {
+    from_bgcolor: String,
+    from_color: String,
+    to_bgcolor: String,
+    to_color: String,
+    frames: u32,
+    progress: u32,
+    easing: Easing,
+}
+
+impl ColorTransition {
+    // (bgcolor, color) for the current progress.
+    pub fn colors(&self) -> (String, String) {
+        let t = self.easing.apply(
+            self.progress as f64
+                / self.frames.max(1) as f64,
+        );
+        (
+            lerp_hex(
+                t,
+                &self.from_bgcolor,
+                &self.to_bgcolor,
+            ),
+            lerp_hex(
+                t,
+                &self.from_color,
+                &self.to_color,
+            ),
+        )
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress >= self.frames
+    }
+}
+
+// For 'Canvas.ramp_speed'. Same as
+// 'ColorTransition', but for the speed.
+#[derive(Debug, Clone)]
+pub struct SpeedRamp {
+    from: f64,
+    to: f64,
+    frames: u32,
+    progress: u32,
+    easing: Easing,
+}
+
+impl SpeedRamp {
+    pub fn speed(&self) -> f64 {
+        if self.is_done() {
+            return self.to;
+        }
+        let t = self.easing.apply(
+            self.progress as f64
+                / self.frames.max(1) as f64,
+        );
+        lerp(t, self.from, self.to)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress >= self.frames
+    }
+}
+
+// Fill/stroke styles derived from colors.
+// We pass strings (not 'JsValue's) to
+// the '*_str' setters, so the only cost left
+// is building these strings, which we now
+// do only when colors change (instead of
+// every frame).
+#[derive(Debug, Clone, Default)]
+pub struct ColorStyles {
+    // 'None' when the canvas is cleared.
+    pub background: Option<String>,
+    pub debug: String,
+    // For 'velocity_coloring'.
+    pub velocity: Vec<String>,
+}
+
+// Sizes we compute in 'resize'
+// (for debugging layouts from JS).
+#[derive(Serialize, Debug)]
+pub struct Metrics {
+    dpr: f64,
+    width: f64,
+    height: f64,
+    unit_size: f64,
+    unit_size_x: f64,
+    unit_size_y: f64,
+    num_of_horizontal_grids: usize,
+    num_of_vertical_grids: usize,
+    particle_size: f64,
+    time_divisor: f64,
+    resolution_scale: f64,
+    frame: i32,
+}
+
+// How the length of sticks is determined.
+// 'Proximity' gets longer as the closest
+// particles get closer. 'Density' gets longer
+// as more particles are around.
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum StickMode {
+    Proximity,
+    Density,
+}
+
+// Ends of sticks. 'Round' makes them look
+// like little dots with tails.
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LineCap {
+    Butt,
+    Round,
+    Square,
+}
+
+impl LineCap {
+    // For 'lineCap' of the context.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineCap::Butt => "butt",
+            LineCap::Round => "round",
+            LineCap::Square => "square",
+        }
+    }
+}
+
+// For 'StickMode::Proximity'. How sharply
+// sticks shrink as particles get farther.
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FalloffCurve {
+    Linear,
+    Quadratic,
+    Ease,
+}
+
+impl FalloffCurve {
+    // 'ratio' is from 0.0 to 1.0.
+    pub fn apply(&self, ratio: f64) -> f64 {
+        match self {
+            FalloffCurve::Linear => ratio,
+            FalloffCurve::Quadratic => ratio.powi(2),
+            FalloffCurve::Ease => {
+                ease_in_out_quad(ratio)
+            }
+        }
+    }
+}
+
+// Particles are drawn rotated by their
+// angles, so 'Triangle' points the way
+// the flow goes (like arrowheads).
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ParticleShape {
+    Circle,
+    Square,
+    Triangle,
 }
 
 // As a browser resizes, we get
@@ -69,68 +320,1041 @@ pub struct Particle {
 // for desktop or mobile.
 #[derive(Debug, Clone)]
 pub struct Canvas {
+    // Device pixels (of the buffer) per CSS
+    // pixel. 'resolution_scale' is included.
     pub dpr: f64,
+    // Kept for 'Config::from_canvas'.
+    pub max_dpr: Option<f64>,
+    pub use_dpr: bool,
+    pub resolution_scale: f64,
     pub el: HtmlCanvasElement,
     pub ctx: CanvasRenderingContext2d,
-    pub width: f64,
-    pub height: f64,
+    // Particles and their movement.
+    pub sim: Simulation,
     pub bgcolor: String,
     pub color: String,
     pub color2: String,
-    pub noise: Perlin,
-    pub frame: i32,
-    pub particles: Vec<Particle>,
-    pub unit_size: f64,
-    pub particle_size: f64,
+    pub color2_intensity: f64,
+    // Particles are colored from it (instead
+    // of 'color') when not empty, which also
+    // takes over 'velocity_coloring'.
+    pub palette: Vec<String>,
+    pub ripple_range_units: f64,
+    pub stick_mode: StickMode,
+    // Sticks take the average angle of
+    // this many closest particles.
+    pub stick_neighbors: usize,
+    // Sticks are as short as 'stick_min_length'
+    // when particles are far (or sparse), and
+    // as long as 'stick_max_length' when close
+    // (or dense). 'None' for 'unit_size'.
+    pub stick_min_length: f64,
+    pub stick_max_length: Option<f64>,
+    // In CSS pixels. Multiplied by 'dpr' when
+    // drawing so that sticks look the same
+    // on any screen.
+    pub stick_width: f64,
+    pub stick_cap: LineCap,
+    pub stick_falloff: FalloffCurve,
+    pub particle_shape: ParticleShape,
+    // 0.0 fully clears the canvas every frame.
+    // Larger the value, longer the trails.
+    pub trail_alpha: f64,
+    // Shade faster particles toward 'color2'.
+    pub velocity_coloring: bool,
+    // Turn it off for particles only
+    // (also saves the most expensive part).
+    pub draw_sticks: bool,
+    // Turn it off for sticks only.
+    pub draw_particles: bool,
+    // Particles are drawn as lines from where
+    // they were in the previous frame.
+    pub streaks: bool,
+    // Sticks bend toward the flow at their
+    // tips (straight by default).
+    pub curved_sticks: bool,
+    // In pixels. When given, particles closer
+    // than this are joined by lines which
+    // fade as they get apart.
+    pub link_distance: Option<f64>,
+    // Off for a crisp, pixelated look when
+    // the buffer is copied to the canvas.
+    pub image_smoothing: bool,
+    // Sticks and particles fade in over
+    // the first frames. 0 to disable.
+    pub fade_in_frames: u32,
+    // For fading in, color transitions,
+    // and speed ramps.
+    pub easing: Easing,
+    // Logs sizes to the console on resize.
+    pub verbose: bool,
+    // Draws the grid which sticks sit on.
+    pub debug_grid: bool,
+    // Draws arrows of the flow itself at each
+    // grid point (regardless of particles).
+    pub field_debug: bool,
+    pub color_transition: Option<ColorTransition>,
+    pub speed_ramp: Option<SpeedRamp>,
+    pub styles: ColorStyles,
+    // Spacing of sticks for each axis.
+    // The smaller of the two is 'sim.unit_size'
+    // which scales stick lengths and
+    // the ripple range.
+    pub unit_size_x: f64,
+    pub unit_size_y: f64,
+    pub mobile_breakpoint: f64,
+    pub particle_size_mobile: f64,
+    pub particle_size_desktop: f64,
+    pub grid_size_mobile: f64,
+    pub grid_size_desktop: f64,
     pub num_of_horizontal_grids: usize,
     pub num_of_vertical_grids: usize,
+    // Offscreen canvas for double buffering.
+    pub buffer: Option<(
+        HtmlCanvasElement,
+        CanvasRenderingContext2d,
+    )>,
+    // Kept (instead of forgetting it) so that
+    // we can drop it in 'unregister_listeners'.
+    pub resize_listener:
+        Option<Rc<Closure<dyn FnMut()>>>,
 }
 
-impl Canvas {
+// Options for 'Canvas' with their defaults.
+// Chain the setters, and then 'build' it
+// with the element to draw on.
+#[derive(Debug, Clone)]
+pub struct CanvasBuilder {
+    bgcolor: String,
+    color: String,
+    num_of_particles: usize,
+    color2_intensity: f64,
+    palette: Vec<String>,
+    seed: Option<u32>,
+    speed: f64,
+    edge_behavior: EdgeBehavior,
+    wrap_margin: Option<f64>,
+    flow_mode: FlowMode,
+    noise_scale: f64,
+    time_scale: f64,
+    time_divisor: f64,
+    angle_turns: f64,
+    jitter: f64,
+    octaves: u32,
+    lacunarity: f64,
+    persistence: f64,
+    wind: (f64, f64),
+    particle_alpha: (f64, f64),
+    size_jitter: f64,
+    ripple_range_units: f64,
+    stick_mode: StickMode,
+    stick_neighbors: usize,
+    stick_min_length: f64,
+    stick_max_length: Option<f64>,
+    stick_width: f64,
+    stick_cap: LineCap,
+    stick_falloff: FalloffCurve,
+    particle_shape: ParticleShape,
+    spawn_shape: SpawnShape,
+    intro_burst: bool,
+    particle_lifetime: Option<u32>,
+    separation_radius: Option<f64>,
+    trail_alpha: f64,
+    velocity_coloring: bool,
+    draw_sticks: bool,
+    draw_particles: bool,
+    streaks: bool,
+    curved_sticks: bool,
+    image_smoothing: bool,
+    link_distance: Option<f64>,
+    fade_in_frames: u32,
+    easing: Easing,
+    verbose: bool,
+    max_dpr: Option<f64>,
+    use_dpr: bool,
+    resolution_scale: f64,
+    double_buffer: bool,
+    mobile_breakpoint: f64,
+    particle_size_mobile: f64,
+    particle_size_desktop: f64,
+    grid_size_mobile: f64,
+    grid_size_desktop: f64,
+}
+
+impl CanvasBuilder {
     pub fn new(
-        el: HtmlCanvasElement,
         bgcolor: String,
         color: String,
     ) -> Self {
-        let ctx = get_ctx(&el).unwrap();
-        let dpr: f64 = device_pixel_ratio();
-        let color2 = color_change_intensity_hex(
-            &color,
-            SECOND_COLOR_INTENSITY,
+        CanvasBuilder {
+            bgcolor,
+            color,
+            num_of_particles: NUM_OF_PARTICLES,
+            color2_intensity: SECOND_COLOR_INTENSITY,
+            palette: Vec::new(),
+            seed: None,
+            speed: SPEED,
+            edge_behavior: EdgeBehavior::Wrap,
+            wrap_margin: None,
+            flow_mode: FlowMode::Perlin,
+            noise_scale: 1.0,
+            time_scale: 1.0,
+            time_divisor: TIME_DIVISOR,
+            angle_turns: 1.0,
+            jitter: JITTER,
+            octaves: 1,
+            lacunarity: 2.0,
+            persistence: 0.5,
+            wind: (0.0, 0.0),
+            particle_alpha: (1.0, 1.0),
+            size_jitter: 0.0,
+            ripple_range_units: RIPPLE_RANGE_UNITS,
+            stick_mode: StickMode::Proximity,
+            stick_neighbors: STICK_NEIGHBORS,
+            stick_min_length: STICK_MIN_LENGTH,
+            stick_max_length: None,
+            stick_width: 1.0,
+            stick_cap: LineCap::Butt,
+            stick_falloff: FalloffCurve::Linear,
+            particle_shape: ParticleShape::Circle,
+            spawn_shape: SpawnShape::Rect,
+            intro_burst: false,
+            particle_lifetime: None,
+            separation_radius: None,
+            trail_alpha: 0.0,
+            velocity_coloring: false,
+            draw_sticks: true,
+            draw_particles: true,
+            streaks: false,
+            curved_sticks: false,
+            image_smoothing: true,
+            link_distance: None,
+            fade_in_frames: 0,
+            easing: Easing::InOutQuad,
+            verbose: false,
+            max_dpr: None,
+            use_dpr: true,
+            resolution_scale: 1.0,
+            double_buffer: false,
+            mobile_breakpoint: MOBILE_BREAKPOINT,
+            particle_size_mobile:
+                PARTICLE_SIZE_MOBILE,
+            particle_size_desktop:
+                PARTICLE_SIZE_DESKTOP,
+            grid_size_mobile: GRID_SIZE_MOBILE,
+            grid_size_desktop: GRID_SIZE_DESKTOP,
+        }
+    }
+
+    pub fn num_particles(mut self, v: usize) -> Self {
+        self.num_of_particles = v;
+        self
+    }
+
+    pub fn color2_intensity(
+        mut self,
+        v: f64,
+    ) -> Self {
+        self.color2_intensity = v;
+        self
+    }
+
+    pub fn palette(mut self, v: Vec<String>) -> Self {
+        self.palette = v;
+        self
+    }
+
+    pub fn seed(mut self, v: u32) -> Self {
+        self.seed = Some(v);
+        self
+    }
+
+    pub fn speed(mut self, v: f64) -> Self {
+        self.speed = v;
+        self
+    }
+
+    pub fn edge_behavior(
+        mut self,
+        v: EdgeBehavior,
+    ) -> Self {
+        self.edge_behavior = v;
+        self
+    }
+
+    pub fn wrap_margin(mut self, v: f64) -> Self {
+        self.wrap_margin = Some(v);
+        self
+    }
+
+    pub fn flow_mode(mut self, v: FlowMode) -> Self {
+        self.flow_mode = v;
+        self
+    }
+
+    pub fn noise_scale(mut self, v: f64) -> Self {
+        self.noise_scale = v;
+        self
+    }
+
+    pub fn time_scale(mut self, v: f64) -> Self {
+        self.time_scale = v;
+        self
+    }
+
+    pub fn time_divisor(mut self, v: f64) -> Self {
+        self.time_divisor = v;
+        self
+    }
+
+    pub fn angle_turns(mut self, v: f64) -> Self {
+        self.angle_turns = v;
+        self
+    }
+
+    pub fn jitter(mut self, v: f64) -> Self {
+        self.jitter = v;
+        self
+    }
+
+    pub fn octaves(mut self, v: u32) -> Self {
+        self.octaves = v;
+        self
+    }
+
+    pub fn lacunarity(mut self, v: f64) -> Self {
+        self.lacunarity = v;
+        self
+    }
+
+    pub fn persistence(mut self, v: f64) -> Self {
+        self.persistence = v;
+        self
+    }
+
+    pub fn wind(mut self, x: f64, y: f64) -> Self {
+        self.wind = (x, y);
+        self
+    }
+
+    // Each particle gets a random opacity
+    // between them.
+    pub fn particle_alpha(
+        mut self,
+        min: f64,
+        max: f64,
+    ) -> Self {
+        self.particle_alpha = (min, max);
+        self
+    }
+
+    // 0.0 for the same size. 0.5 for sizes
+    // from half to 1.5 times.
+    pub fn size_jitter(mut self, v: f64) -> Self {
+        self.size_jitter = v;
+        self
+    }
+
+    pub fn ripple_range_units(
+        mut self,
+        v: f64,
+    ) -> Self {
+        self.ripple_range_units = v;
+        self
+    }
+
+    pub fn stick_mode(
+        mut self,
+        v: StickMode,
+    ) -> Self {
+        self.stick_mode = v;
+        self
+    }
+
+    pub fn stick_neighbors(
+        mut self,
+        v: usize,
+    ) -> Self {
+        self.stick_neighbors = v;
+        self
+    }
+
+    pub fn stick_min_length(
+        mut self,
+        v: f64,
+    ) -> Self {
+        self.stick_min_length = v;
+        self
+    }
+
+    pub fn stick_max_length(
+        mut self,
+        v: f64,
+    ) -> Self {
+        self.stick_max_length = Some(v);
+        self
+    }
+
+    pub fn stick_width(mut self, v: f64) -> Self {
+        self.stick_width = v;
+        self
+    }
+
+    pub fn stick_cap(mut self, v: LineCap) -> Self {
+        self.stick_cap = v;
+        self
+    }
+
+    pub fn stick_falloff(
+        mut self,
+        v: FalloffCurve,
+    ) -> Self {
+        self.stick_falloff = v;
+        self
+    }
+
+    pub fn particle_shape(
+        mut self,
+        v: ParticleShape,
+    ) -> Self {
+        self.particle_shape = v;
+        self
+    }
+
+    pub fn spawn_shape(
+        mut self,
+        v: SpawnShape,
+    ) -> Self {
+        self.spawn_shape = v;
+        self
+    }
+
+    // Particles burst out from the center
+    // (every time they are generated).
+    pub fn intro_burst(mut self, v: bool) -> Self {
+        self.intro_burst = v;
+        self
+    }
+
+    // In frames. 0 for forever.
+    pub fn particle_lifetime(
+        mut self,
+        v: u32,
+    ) -> Self {
+        self.particle_lifetime =
+            Some(v).filter(|&v| v > 0);
+        self
+    }
+
+    // In pixels. 0 to disable.
+    pub fn separation_radius(
+        mut self,
+        v: f64,
+    ) -> Self {
+        self.separation_radius =
+            Some(v).filter(|&v| v > 0.0);
+        self
+    }
+
+    pub fn trail_alpha(mut self, v: f64) -> Self {
+        self.trail_alpha = v;
+        self
+    }
+
+    pub fn velocity_coloring(
+        mut self,
+        v: bool,
+    ) -> Self {
+        self.velocity_coloring = v;
+        self
+    }
+
+    pub fn draw_sticks(mut self, v: bool) -> Self {
+        self.draw_sticks = v;
+        self
+    }
+
+    pub fn draw_particles(mut self, v: bool) -> Self {
+        self.draw_particles = v;
+        self
+    }
+
+    pub fn streaks(mut self, v: bool) -> Self {
+        self.streaks = v;
+        self
+    }
+
+    pub fn curved_sticks(mut self, v: bool) -> Self {
+        self.curved_sticks = v;
+        self
+    }
+
+    pub fn image_smoothing(
+        mut self,
+        v: bool,
+    ) -> Self {
+        self.image_smoothing = v;
+        self
+    }
+
+    pub fn link_distance(mut self, v: f64) -> Self {
+        self.link_distance = Some(v);
+        self
+    }
+
+    pub fn fade_in_frames(mut self, v: u32) -> Self {
+        self.fade_in_frames = v;
+        self
+    }
+
+    pub fn easing(mut self, v: Easing) -> Self {
+        self.easing = v;
+        self
+    }
+
+    // Higher ratios (ex. 3x or 4x phones) are
+    // capped to it. Smaller buffers, but less
+    // sharp.
+    pub fn max_dpr(mut self, v: f64) -> Self {
+        self.max_dpr = Some(v);
+        self
+    }
+
+    // When off, the buffer is in CSS pixels
+    // (as if the ratio was always 1.0).
+    // Blurry on HiDPI screens, but much less
+    // to draw (ex. 1/4 of the pixels on 2x).
+    pub fn use_dpr(mut self, v: bool) -> Self {
+        self.use_dpr = v;
+        self
+    }
+
+    // Below 1.0, the buffer gets smaller (ex.
+    // half the width and height for 0.5) while
+    // the element stays in the same size, and
+    // the browser scales it up. Chunky (crisp
+    // with 'image_smoothing' off), and much
+    // less to draw.
+    pub fn resolution_scale(
+        mut self,
+        v: f64,
+    ) -> Self {
+        // NaN would make the canvas 0x0.
+        self.resolution_scale = if v.is_finite() {
+            v.clamp(MIN_RESOLUTION_SCALE, 1.0)
+        } else {
+            1.0
+        };
+        self
+    }
+
+    pub fn verbose(mut self, v: bool) -> Self {
+        self.verbose = v;
+        self
+    }
+
+    pub fn double_buffer(mut self, v: bool) -> Self {
+        self.double_buffer = v;
+        self
+    }
+
+    pub fn mobile_breakpoint(
+        mut self,
+        v: f64,
+    ) -> Self {
+        self.mobile_breakpoint = v;
+        self
+    }
+
+    pub fn particle_size_mobile(
+        mut self,
+        v: f64,
+    ) -> Self {
+        self.particle_size_mobile = v;
+        self
+    }
+
+    pub fn particle_size_desktop(
+        mut self,
+        v: f64,
+    ) -> Self {
+        self.particle_size_desktop = v;
+        self
+    }
+
+    pub fn grid_size_mobile(
+        mut self,
+        v: f64,
+    ) -> Self {
+        self.grid_size_mobile = v;
+        self
+    }
+
+    pub fn grid_size_desktop(
+        mut self,
+        v: f64,
+    ) -> Self {
+        self.grid_size_desktop = v;
+        self
+    }
+
+    // Values are clamped here (the same way
+    // the setters on 'Canvas' do), and sizes
+    // are taken from the element, so the canvas
+    // is ready to be drawn as soon as it is built.
+    pub fn build(
+        self,
+        el: HtmlCanvasElement,
+    ) -> Result<Canvas, DomError> {
+        let ctx = get_ctx(&el)?;
+        // Everything in device pixels (sizes,
+        // line widths, pointer positions) goes
+        // through 'dpr', so scaling it scales
+        // all of them at once.
+        let dpr: f64 = if self.use_dpr {
+            capped_dpr(
+                device_pixel_ratio(),
+                self.max_dpr,
+            )
+        } else {
+            1.0
+        } * self.resolution_scale;
+        let color2 = derive_color2(
+            &self.color,
+            self.color2_intensity,
         );
 
-        ctx.scale(dpr, dpr).unwrap_or(());
+        if self.use_dpr {
+            ctx.scale(dpr, dpr).unwrap_or(());
+        }
+
+        // Colors which are not hex codes
+        // are ignored.
+        let palette: Vec<String> = self
+            .palette
+            .into_iter()
+            .filter(|c| hex_to_rgb(c).is_ok())
+            .collect();
+
+        // Max should not be shorter than min.
+        let stick_min_length =
+            self.stick_min_length.max(0.0);
+        let stick_max_length = self
+            .stick_max_length
+            .map(|v| v.max(stick_min_length));
+
+        let mut sim = Simulation::new(
+            self.seed,
+            self.num_of_particles
+                .min(MAX_NUM_OF_PARTICLES),
+        );
+        sim.set_speed(self.speed);
+        sim.edge_behavior = self.edge_behavior;
+        sim.wrap_margin =
+            self.wrap_margin.map(|v| v.max(0.0));
+        sim.flow_mode = self.flow_mode;
+        sim.noise_scale = self.noise_scale;
+        sim.time_scale = self.time_scale;
+        sim.time_divisor = if self.time_divisor > 0.0
+        {
+            self.time_divisor
+        } else {
+            TIME_DIVISOR
+        };
+        sim.jitter = self.jitter.max(0.0);
+        sim.angle_turns = self.angle_turns;
+        sim.octaves = self.octaves.max(1);
+        sim.lacunarity = self.lacunarity;
+        sim.persistence = self.persistence.max(0.0);
+        sim.wind = self.wind;
+        sim.alpha_range = self.particle_alpha;
+        sim.size_jitter = self.size_jitter;
+        sim.particle_lifetime =
+            self.particle_lifetime;
+        sim.separation_radius =
+            self.separation_radius;
+        sim.spawn_shape = self.spawn_shape;
+        sim.intro_burst = self.intro_burst;
+        sim.num_of_colors = palette.len().max(1);
 
-        Canvas {
+        let mut canvas = Canvas {
             dpr,
+            max_dpr: self.max_dpr,
+            use_dpr: self.use_dpr,
+            resolution_scale: self.resolution_scale,
             el,
             ctx,
-            width: 100.0,
-            height: 100.0,
-            bgcolor,
-            color,
+            sim,
+            bgcolor: self.bgcolor,
+            color: self.color,
             color2,
-            noise: Perlin::new(),
-            frame: 0,
-            particles: Vec::new(),
-            unit_size: 1.0,
-            particle_size: 0.1,
-            num_of_horizontal_grids: 10,
-            num_of_vertical_grids: 10,
+            color2_intensity: self.color2_intensity,
+            palette,
+            ripple_range_units: RIPPLE_RANGE_UNITS,
+            stick_mode: self.stick_mode,
+            stick_neighbors: self
+                .stick_neighbors
+                .max(1),
+            stick_min_length,
+            stick_max_length,
+            stick_width: 1.0,
+            stick_cap: self.stick_cap,
+            stick_falloff: self.stick_falloff,
+            particle_shape: self.particle_shape,
+            trail_alpha: 0.0,
+            velocity_coloring: self.velocity_coloring,
+            draw_sticks: self.draw_sticks,
+            draw_particles: self.draw_particles,
+            streaks: self.streaks,
+            curved_sticks: self.curved_sticks,
+            image_smoothing: self.image_smoothing,
+            link_distance: self
+                .link_distance
+                .filter(|&d| d > 0.0),
+            fade_in_frames: self.fade_in_frames,
+            easing: self.easing,
+            verbose: self.verbose,
+            debug_grid: false,
+            field_debug: false,
+            color_transition: None,
+            speed_ramp: None,
+            styles: ColorStyles::default(),
+            unit_size_x: 1.0,
+            unit_size_y: 1.0,
+            mobile_breakpoint: if self
+                .mobile_breakpoint
+                .is_nan()
+            {
+                MOBILE_BREAKPOINT
+            } else {
+                self.mobile_breakpoint.max(0.0)
+            },
+            particle_size_mobile: self
+                .particle_size_mobile,
+            particle_size_desktop: self
+                .particle_size_desktop,
+            grid_size_mobile: valid_grid_size(
+                self.grid_size_mobile,
+                GRID_SIZE_MOBILE,
+            ),
+            grid_size_desktop: valid_grid_size(
+                self.grid_size_desktop,
+                GRID_SIZE_DESKTOP,
+            ),
+            num_of_horizontal_grids: 0,
+            num_of_vertical_grids: 0,
+            buffer: None,
+            resize_listener: None,
+        };
+
+        canvas.set_ripple_range_units(
+            self.ripple_range_units,
+        );
+        // Also prepares 'styles'.
+        canvas.set_trail_alpha(self.trail_alpha);
+        canvas.set_stick_width(self.stick_width);
+        if self.double_buffer {
+            canvas.enable_double_buffer()?;
+        }
+        canvas.update_size();
+        Ok(canvas)
+    }
+}
+
+impl Canvas {
+    // Kept for backward compatibility.
+    // Use 'CanvasBuilder' for other options.
+    pub fn new(
+        el: HtmlCanvasElement,
+        bgcolor: String,
+        color: String,
+        num_of_particles: Option<usize>,
+        color2_intensity: Option<f64>,
+        seed: Option<u32>,
+    ) -> Result<Self, DomError> {
+        let mut builder =
+            CanvasBuilder::new(bgcolor, color);
+        if let Some(v) = num_of_particles {
+            builder = builder.num_particles(v);
+        }
+        if let Some(v) = color2_intensity {
+            builder = builder.color2_intensity(v);
+        }
+        if let Some(v) = seed {
+            builder = builder.seed(v);
+        }
+        builder.build(el)
+    }
+
+    // Called when colors are changed from JS.
+    // Neither 'frame' nor particles are reset
+    // so that the animation continues.
+    pub fn set_colors(
+        &mut self,
+        bgcolor: String,
+        color: String,
+    ) {
+        self.color_transition = None;
+        self.bgcolor = bgcolor;
+        self.color = color;
+        self.recompute_color2();
+    }
+
+    // Gradually changes colors over 'frames'.
+    // Starts from the current colors (even in
+    // the middle of another transition).
+    pub fn transition_colors(
+        &mut self,
+        bgcolor: String,
+        color: String,
+        frames: u32,
+    ) {
+        self.color_transition =
+            Some(ColorTransition {
+                from_bgcolor: self.bgcolor.clone(),
+                from_color: self.color.clone(),
+                to_bgcolor: bgcolor,
+                to_color: color,
+                frames,
+                progress: 0,
+                easing: self.easing,
+            });
+    }
+
+    fn advance_color_transition(&mut self) {
+        if let Some(transition) =
+            &mut self.color_transition
+        {
+            transition.progress += 1;
+            let (bgcolor, color) =
+                transition.colors();
+            if transition.is_done() {
+                self.color_transition = None;
+            }
+            self.bgcolor = bgcolor;
+            self.color = color;
+            self.recompute_color2();
+        }
+    }
+
+    // 'color2' (for sticks) is derived from 'color'.
+    // Call this when either 'color' or
+    // 'color2_intensity' changes.
+    pub fn recompute_color2(&mut self) {
+        self.color2 = derive_color2(
+            &self.color,
+            self.color2_intensity,
+        );
+        self.recompute_styles();
+    }
+
+    // Call this when 'bgcolor', 'color',
+    // 'color2', or 'trail_alpha' changes.
+    pub fn recompute_styles(&mut self) {
+        self.styles = ColorStyles {
+            background: background_fill(
+                &self.bgcolor,
+                self.trail_alpha,
+            ),
+            debug: derive_color2(
+                &self.color,
+                DEBUG_GRID_INTENSITY,
+            ),
+            velocity: gradient_steps(
+                &self.color,
+                &self.color2,
+                VELOCITY_COLOR_STEPS,
+            ),
+        };
+    }
+
+    // Setting it to 0 freezes particles.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed_ramp = None;
+        self.sim.set_speed(speed);
+    }
+
+    // Gradually changes the speed over 'frames'
+    // (ex. for intros and outros).
+    pub fn ramp_speed(
+        &mut self,
+        to: f64,
+        frames: u32,
+    ) {
+        self.speed_ramp = Some(SpeedRamp {
+            from: self.sim.speed,
+            to,
+            frames,
+            progress: 0,
+            easing: self.easing,
+        });
+    }
+
+    fn advance_speed_ramp(&mut self) {
+        if let Some(ramp) = &mut self.speed_ramp {
+            ramp.progress += 1;
+            let speed = ramp.speed();
+            if ramp.is_done() {
+                self.speed_ramp = None;
+            }
+            self.sim.set_speed(speed);
+        }
+    }
+
+    // Smaller the value, sticks hug
+    // the closest particles more tightly.
+    pub fn set_ripple_range_units(
+        &mut self,
+        units: f64,
+    ) {
+        self.ripple_range_units =
+            units.max(MIN_RIPPLE_RANGE_UNITS);
+    }
+
+    // Creates an offscreen buffer, and
+    // 'draw' will render into it.
+    pub fn enable_double_buffer(
+        &mut self,
+    ) -> Result<(), DomError> {
+        let el = create_canvas()?;
+        let ctx = get_ctx(&el)?;
+        el.set_width(self.el.width());
+        el.set_height(self.el.height());
+        self.buffer = Some((el, ctx));
+        self.apply_image_smoothing();
+        Ok(())
+    }
+
+    pub fn apply_image_smoothing(&self) {
+        self.ctx.set_image_smoothing_enabled(
+            self.image_smoothing,
+        );
+        if let Some((_, ctx)) = &self.buffer {
+            ctx.set_image_smoothing_enabled(
+                self.image_smoothing,
+            );
+        }
+    }
+
+    // Overrides the size picked by the breakpoint
+    // until the next resize.
+    // Since particles move by 'speed' times
+    // their size, larger particles also move
+    // faster. Lower 'speed' to compensate.
+    pub fn set_particle_size(&mut self, size: f64) {
+        self.sim.particle_size =
+            size.max(MIN_PARTICLE_SIZE);
+    }
+
+    pub fn set_stick_width(&mut self, width: f64) {
+        self.stick_width = width.max(0.0);
+    }
+
+    pub fn set_trail_alpha(&mut self, alpha: f64) {
+        // 1.0 would never clear the canvas.
+        self.trail_alpha = alpha.clamp(0.0, 0.99);
+        self.recompute_styles();
+    }
+
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        Snapshot {
+            frame: self.sim.frame,
+            width: self.sim.width,
+            height: self.sim.height,
+            angle_turns: self.sim.angle_turns,
+            particles: &self.sim.particles,
+        }
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            dpr: self.dpr,
+            width: self.sim.width,
+            height: self.sim.height,
+            unit_size: self.sim.unit_size,
+            unit_size_x: self.unit_size_x,
+            unit_size_y: self.unit_size_y,
+            num_of_horizontal_grids: self
+                .num_of_horizontal_grids,
+            num_of_vertical_grids: self
+                .num_of_vertical_grids,
+            particle_size: self.sim.particle_size,
+            time_divisor: self.sim.time_divisor,
+            resolution_scale: self.resolution_scale,
+            frame: self.sim.frame,
+        }
+    }
+
+    // Up to 'MAX_NUM_OF_PARTICLES' in total.
+    pub fn add_particles(&mut self, n: usize) {
+        let room = MAX_NUM_OF_PARTICLES
+            .saturating_sub(self.sim.particles.len());
+        self.sim.add_particles(n.min(room));
+    }
+
+    pub fn remove_particles(&mut self, n: usize) {
+        self.sim.remove_particles(n);
+    }
+
+    // Only particles. Colors and sizes stay.
+    pub fn reset(&mut self) {
+        self.sim.reset();
+    }
+
+    // The same lookup we do for sticks.
+    pub fn nearest_particle(
+        &self,
+        x: f64,
+        y: f64,
+    ) -> Option<&Particle> {
+        closest_two(
+            &self.sim.particles,
+            x,
+            y,
+            self.wrap_size(),
+        )
+        .map(|(parts, _)| parts[0])
+    }
+
+    // With 'EdgeBehavior::Wrap', particles
+    // near the opposite edge are also close
+    // (they come through the edge).
+    fn wrap_size(&self) -> Option<(f64, f64)> {
+        match self.sim.edge_behavior {
+            EdgeBehavior::Wrap => Some((
+                self.sim.width,
+                self.sim.height,
+            )),
+            _ => None,
         }
     }
 
     // Although we want 'update_size' to run
     // as browser size changes, we want
     // to debounce the event by 500 msec.
-    pub fn register_listeners(&mut self) {
-        let self_rc =
-            Rc::new(RefCell::new(self.clone()));
+    //
+    // It takes the same 'Rc' which 'Proxy' holds
+    // so that the listener resizes the canvas
+    // actually being rendered (we used to give it
+    // a clone of the canvas, and the visible one
+    // was never resized).
+    // Since the canvas keeps the listener,
+    // the listener holds the canvas weakly
+    // to avoid a reference cycle.
+    pub fn register_listeners(
+        canvas_rc: &Rc<RefCell<Canvas>>,
+    ) {
+        let canvas_weak = Rc::downgrade(canvas_rc);
 
         let mut debounced_update_size = debounce(
             move || {
-                let mut canvas = self_rc.borrow_mut();
-                canvas.update_size();
+                if let Some(canvas_rc) =
+                    canvas_weak.upgrade()
+                {
+                    canvas_rc
+                        .borrow_mut()
+                        .update_size();
+                }
             },
             Duration::from_millis(500),
         );
@@ -141,157 +1365,571 @@ impl Canvas {
             })
                 as Box<dyn FnMut()>);
 
+        // Instead of 'set_onresize' (which there
+        // can be only one for the window), we add
+        // a listener for each canvas so that
+        // multiple instances can coexist.
         get_window()
             .expect("No window")
-            .set_onresize(Some(
+            .add_event_listener_with_callback(
+                "resize",
                 callback.as_ref().unchecked_ref(),
+            )
+            .expect("Failed to add resize listener");
+
+        // prevent closure being dropped soon
+        canvas_rc.borrow_mut().resize_listener =
+            Some(Rc::new(callback));
+    }
+
+    pub fn unregister_listeners(&mut self) {
+        if let (Ok(window), Some(callback)) =
+            (get_window(), &self.resize_listener)
+        {
+            window
+                .remove_event_listener_with_callback(
+                    "resize",
+                    callback
+                        .as_ref()
+                        .as_ref()
+                        .unchecked_ref(),
+                )
+                .unwrap_or(());
+        }
+        self.resize_listener = None;
+    }
+
+    // Called when browser size changes.
+    pub fn update_size(&mut self) {
+        let (w, h): (f64, f64) =
+            get_canvas_size(&self.el);
+        self.resize(w, h);
+    }
+
+    // Same as 'update_size', but with the given
+    // size (in CSS pixels) instead of that of
+    // the element (ex. to export a frame
+    // in a fixed resolution).
+    pub fn resize(
+        &mut self,
+        css_width: f64,
+        css_height: f64,
+    ) {
+        let (w, h) = (css_width, css_height);
+
+        let (particle_size, grid_size) =
+            if w < self.mobile_breakpoint {
+                (
+                    self.particle_size_mobile,
+                    self.grid_size_mobile,
+                )
+            } else {
+                (
+                    self.particle_size_desktop,
+                    self.grid_size_desktop,
+                )
+            };
+
+        let width: f64 = w * self.dpr;
+        let height: f64 = h * self.dpr;
+
+        // 'grid_size' columns across the width.
+        // For rows, we take as many as fit
+        // the height with cells closest to
+        // square, and stretch them slightly
+        // so that they fill the height exactly.
+        let unit_size_x = width / grid_size;
+        let rows =
+            (height / unit_size_x).round().max(1.0);
+        let unit_size_y = height / rows;
+
+        self.unit_size_x = unit_size_x;
+        self.unit_size_y = unit_size_y;
+        self.sim.unit_size =
+            unit_size_x.min(unit_size_y);
+        self.sim.particle_size = particle_size;
+
+        self.num_of_horizontal_grids = rows as usize;
+        self.num_of_vertical_grids =
+            (width / unit_size_x).ceil() as usize;
+
+        // Used to be always logged. Now silent
+        // unless 'verbose' is given.
+        if self.verbose {
+            console::log_1(
+                &("[canvas] Updating canvas size"
+                    .into()),
+            );
+
+            console::log_1(
+                &(format!(
+                    "[canvas] {} x {}",
+                    lazy_round(width),
+                    lazy_round(height)
+                )
+                .into()),
+            );
+
+            console::log_1(
+                &(format!(
+                    "[canvas] particle_size: {}",
+                    lazy_round(particle_size)
+                )
+                .into()),
+            );
+
+            console::log_1(
+                &(format!(
+                    "[canvas] grid_size: {}",
+                    lazy_round(grid_size)
+                )
+                .into()),
+            );
+        }
+
+        // mosaikekkan
+        // if let Err(err) = get_window().map(|window| {
+        //     window.alert_with_message(
+        //         &(format!(
+        //             "width: {}",
+        //             lazy_round(width)
+        //         )),
+        //     )
+        // }) {
+        //     console::log_1(
+        //         &(format!("ERR: {}", err).into()),
+        //     )
+        // }
+
+        let (buffer_width, buffer_height) =
+            buffer_size(w, h, self.dpr);
+        self.el.set_width(buffer_width);
+        self.el.set_height(buffer_height);
+
+        if let Some((el, _)) = &self.buffer {
+            el.set_width(buffer_width);
+            el.set_height(buffer_height);
+        }
+
+        // Resizing resets the context state,
+        // so it has to be set every time.
+        self.apply_image_smoothing();
+
+        // Resets 'frame', and generates
+        // new particles.
+        self.sim.resize(
+            lazy_round(width),
+            lazy_round(height),
+        );
+    }
+
+    // Repeatedly called from 'Proxy.run'.
+    // 'dt' is seconds since the last frame.
+    pub fn update(&mut self, dt: f64) {
+        self.advance_speed_ramp();
+        self.sim.update(dt);
+        self.advance_color_transition();
+    }
+
+    // Repeatedly called from 'Proxy.run'.
+    // When double buffering, we first render
+    // everything into the offscreen buffer,
+    // and then copy it to the visible canvas
+    // at once to avoid tearing.
+    pub fn draw(&mut self) {
+        match self.buffer.clone() {
+            Some((el, ctx)) => {
+                self.render(&ctx);
+                self.ctx.clear_rect(
+                    0_f64,
+                    0_f64,
+                    self.sim.width,
+                    self.sim.height,
+                );
+                self.ctx
+                    .draw_image_with_html_canvas_element(
+                        &el, 0_f64, 0_f64,
+                    )
+                    .unwrap_or(());
+            }
+            None => {
+                let ctx = self.ctx.clone();
+                self.render(&ctx);
+            }
+        }
+    }
+
+    fn render(
+        &mut self,
+        ctx: &CanvasRenderingContext2d,
+    ) {
+        match &self.styles.background {
+            Some(style) => {
+                ctx.set_fill_style_str(style);
+                ctx.fill_rect(
+                    0_f64,
+                    0_f64,
+                    self.sim.width,
+                    self.sim.height,
+                );
+            }
+            None => ctx.clear_rect(
+                0_f64,
+                0_f64,
+                self.sim.width,
+                self.sim.height,
+            ),
+        }
+
+        if self.debug_grid {
+            self.render_debug_grid(ctx);
+        }
+
+        if self.field_debug {
+            self.render_field_debug(ctx);
+        }
+
+        // Only for sticks and particles
+        // (not for the background).
+        // Since 'frame' is reset on resize,
+        // it fades in again after resizing.
+        let fading = self.fade_in_frames > 0
+            && self.sim.frame >= 0
+            && (self.sim.frame as u32)
+                < self.fade_in_frames;
+        if fading {
+            ctx.set_global_alpha(self.easing.apply(
+                self.sim.frame as f64
+                    / self.fade_in_frames as f64,
             ));
+        }
+
+        if self.draw_sticks {
+            self.render_sticks(ctx);
+        }
+
+        // Particles keep moving even when hidden
+        // so that sticks keep following them.
+        if self.draw_particles {
+            self.render_particles(ctx);
+        }
+
+        if let Some(distance) = self.link_distance {
+            self.render_links(ctx, distance);
+        }
+
+        if fading {
+            ctx.set_global_alpha(1.0);
+        }
+    }
+
+    // Lines between nearby particles.
+    // Each has its own alpha, so we can't
+    // stroke them all at once.
+    fn render_links(
+        &self,
+        ctx: &CanvasRenderingContext2d,
+        distance: f64,
+    ) {
+        // Could be fading in.
+        let alpha = ctx.global_alpha();
+        ctx.set_stroke_style_str(&self.color2);
+        ctx.set_line_width(self.dpr);
+
+        let particles = &self.sim.particles;
+        let wrap_size = self.wrap_size();
+        for (i, j, dist) in close_pairs(
+            particles,
+            self.sim.width,
+            self.sim.height,
+            distance,
+            wrap_size.is_some(),
+        ) {
+            let (a, b) =
+                (&particles[i], &particles[j]);
+            ctx.set_global_alpha(
+                alpha * (1.0 - dist / distance),
+            );
+            ctx.begin_path();
+            match wrap_size {
+                // Linked across the edges. Instead
+                // of a line across the canvas,
+                // each one reaches out past its
+                // own edge toward the other.
+                Some((w, h)) => {
+                    let (dx, dy) = toroidal_delta(
+                        a.x, a.y, b.x, b.y, w, h,
+                    );
+                    ctx.move_to(a.x, a.y);
+                    ctx.line_to(a.x + dx, a.y + dy);
+                    if (a.x + dx - b.x).abs() > 0.5
+                        || (a.y + dy - b.y).abs()
+                            > 0.5
+                    {
+                        ctx.move_to(b.x, b.y);
+                        ctx.line_to(
+                            b.x - dx,
+                            b.y - dy,
+                        );
+                    }
+                }
+                None => {
+                    ctx.move_to(a.x, a.y);
+                    ctx.line_to(b.x, b.y);
+                }
+            }
+            ctx.stroke();
+        }
 
-        callback.forget(); // prevent closure being dropped soon
+        ctx.set_global_alpha(alpha);
     }
 
-    // Called when browser size changes.
-    pub fn update_size(&mut self) {
-        let (w, h): (f64, f64) =
-            get_canvas_size(&self.el);
+    fn render_particles(
+        &self,
+        ctx: &CanvasRenderingContext2d,
+    ) {
+        // ------------------------------------
+        // Particles
+        // ------------------------------------
+        // With 'velocity_coloring', the slowest
+        // particle gets 'color', and the fastest
+        // gets 'color2'. Colors in between are
+        // prepared when colors change.
+        // Particles are drawn grouped by colors
+        // so that we set the fill style only
+        // once for each color.
+        let fill_styles: &[String] =
+            if !self.palette.is_empty() {
+                &self.palette
+            } else if self.velocity_coloring {
+                &self.styles.velocity
+            } else {
+                std::slice::from_ref(&self.color)
+            };
+
+        let (min_v, max_v) =
+            self.sim.particles.iter().fold(
+                (f64::MAX, f64::MIN),
+                |(min_v, max_v), p| {
+                    let v = p.velocity();
+                    (min_v.min(v), max_v.max(v))
+                },
+            );
+        let last_step =
+            (fill_styles.len() - 1) as f64;
 
-        self.frame = 0;
+        let mut current_style = None;
+        // Could be fading in.
+        let base_alpha = ctx.global_alpha();
 
-        let (particle_size, grid_size) = if w < 768.0
-        {
-            (PARTICLE_SIZE_MOBILE, GRID_SIZE_MOBILE)
+        let style_of = |p: &Particle| {
+            if !self.palette.is_empty() {
+                p.color_index.min(last_step as usize)
+            } else if max_v - min_v > f64::EPSILON {
+                (norm(p.velocity(), min_v, max_v)
+                    * last_step)
+                    .round() as usize
+            } else {
+                0
+            }
+        };
+        // With only one color, there is nothing
+        // to group (nor to sort every frame).
+        let order: Box<
+            dyn Iterator<Item = (usize, &Particle)>,
+        > = if fill_styles.len() > 1 {
+            let mut order: Vec<(usize, &Particle)> =
+                self.sim
+                    .particles
+                    .iter()
+                    .map(|p| (style_of(p), p))
+                    .collect();
+            order.sort_by_key(|&(style, _)| style);
+            Box::new(order.into_iter())
         } else {
-            (PARTICLE_SIZE_DESKTOP, GRID_SIZE_DESKTOP)
+            Box::new(
+                self.sim
+                    .particles
+                    .iter()
+                    .map(|p| (0, p)),
+            )
         };
 
-        let width: f64 = w * self.dpr;
-        let height: f64 = h * self.dpr;
+        for (style, p) in order {
+            if current_style != Some(style) {
+                ctx.set_fill_style_str(
+                    &fill_styles[style],
+                );
+                ctx.set_stroke_style_str(
+                    &fill_styles[style],
+                );
+                current_style = Some(style);
+            }
 
-        let unit_size = width / grid_size;
+            if self.streaks {
+                self.render_streak(
+                    ctx, p, base_alpha,
+                );
+                continue;
+            }
 
-        self.unit_size = unit_size;
-        self.particle_size = particle_size;
+            // Translate the canvas to the particle position.
+            ctx.save();
+            ctx.translate(p.x, p.y).unwrap_or(());
 
-        self.num_of_horizontal_grids =
-            (height / unit_size).ceil() as usize;
-        self.num_of_vertical_grids =
-            (width / unit_size).ceil() as usize;
+            // Rotate the canvas based on the particle angle.
+            ctx.rotate(p.angle).unwrap_or(());
 
-        self.particles = generate_particles(
-            width,
-            height,
-            NUM_OF_PARTICLES,
-        );
+            let radius = self.sim.particle_size
+                * p.size_factor
+                / 2.0;
 
-        console::log_1(
-            &("[canvas] Updating canvas size".into()),
-        );
+            // Back to 'base_alpha' on 'restore'.
+            if p.alpha < 1.0 {
+                ctx.set_global_alpha(
+                    base_alpha * p.alpha,
+                );
+            }
 
-        console::log_1(
-            &(format!(
-                "[canvas] {} x {}",
-                lazy_round(width),
-                lazy_round(height)
-            )
-            .into()),
-        );
+            match self.particle_shape {
+                ParticleShape::Circle => {
+                    ctx.begin_path();
+                    ctx.arc(
+                        0_f64,
+                        0_f64,
+                        radius,
+                        0_f64,
+                        2.0 * PI,
+                    )
+                    .unwrap_or(());
+                    ctx.fill();
+                }
+                ParticleShape::Square => {
+                    ctx.fill_rect(
+                        -radius,
+                        -radius,
+                        radius * 2.0,
+                        radius * 2.0,
+                    );
+                }
+                ParticleShape::Triangle => {
+                    // Pointing to +x, which is
+                    // the angle after rotating.
+                    ctx.begin_path();
+                    ctx.move_to(radius, 0_f64);
+                    ctx.line_to(-radius, -radius);
+                    ctx.line_to(-radius, radius);
+                    ctx.close_path();
+                    ctx.fill();
+                }
+            }
 
-        console::log_1(
-            &(format!(
-                "[canvas] particle_size: {}",
-                lazy_round(particle_size)
-            )
-            .into()),
-        );
+            ctx.restore();
+        }
+    }
 
-        console::log_1(
-            &(format!(
-                "[canvas] grid_size: {}",
-                lazy_round(grid_size)
-            )
-            .into()),
+    fn render_streak(
+        &self,
+        ctx: &CanvasRenderingContext2d,
+        p: &Particle,
+        base_alpha: f64,
+    ) {
+        // Those wrapped (or respawned) would
+        // draw lines across the canvas.
+        // Also skips NaN.
+        let jump = distance(
+            (p.prev_x, p.prev_y),
+            (p.x, p.y),
         );
+        let max_jump =
+            self.sim.width.min(self.sim.height) / 2.0;
+        if !jump.is_finite() || jump > max_jump {
+            return;
+        }
 
-        // mosaikekkan
-        // if let Err(err) = get_window().map(|window| {
-        //     window.alert_with_message(
-        //         &(format!(
-        //             "width: {}",
-        //             lazy_round(width)
-        //         )),
-        //     )
-        // }) {
-        //     console::log_1(
-        //         &(format!("ERR: {}", err).into()),
-        //     )
-        // }
-
-        self.el.set_width(width as u32);
-        self.el.set_height(height as u32);
-
-        self.width = lazy_round(width);
-        self.height = lazy_round(height);
+        ctx.save();
+        if p.alpha < 1.0 {
+            ctx.set_global_alpha(
+                base_alpha * p.alpha,
+            );
+        }
+        ctx.set_line_width(
+            self.sim.particle_size * p.size_factor,
+        );
+        // So that those not moving are still dots.
+        ctx.set_line_cap("round");
+        ctx.begin_path();
+        ctx.move_to(p.prev_x, p.prev_y);
+        ctx.line_to(p.x, p.y);
+        ctx.stroke();
+        ctx.restore();
     }
 
-    // Repeatedly called from 'Proxy.run'.
-    pub fn update(&mut self) {
-        self.frame += 1;
-        let mut rng = rand::thread_rng();
-
-        for p in &mut self.particles {
-            let w = self.width;
-            let h = self.height;
-
-            // Keep using random values when
-            // generating noise, otherwise,
-            // all particles would have the same
-            // positions and angles which
-            // would not look dynamic at all.
-            let noise_val = self.noise.get([
-                (p.x / w) + rng.gen_range(-0.1, 0.1),
-                (p.y / h) + rng.gen_range(-0.1, 0.1),
-                self.frame as f64 / 100.0,
-            ]);
-
-            let angle = noise_val * PI * 2.0;
-
-            let (dx, dy) = (
-                SPEED * angle.cos(),
-                SPEED * angle.sin(),
-            );
+    // Lines through stick positions.
+    fn render_debug_grid(
+        &self,
+        ctx: &CanvasRenderingContext2d,
+    ) {
+        ctx.set_stroke_style_str(&self.styles.debug);
+        ctx.set_line_width(1.0);
+        ctx.begin_path();
+        for j in 0..self.num_of_vertical_grids {
+            let x = j as f64 * self.unit_size_x;
+            ctx.move_to(x, 0_f64);
+            ctx.line_to(x, self.sim.height);
+        }
+        for i in 0..self.num_of_horizontal_grids {
+            let y = i as f64 * self.unit_size_y;
+            ctx.move_to(0_f64, y);
+            ctx.line_to(self.sim.width, y);
+        }
+        ctx.stroke();
+    }
 
-            let size = self.particle_size;
+    // Arrows pointing where the flow goes.
+    fn render_field_debug(
+        &self,
+        ctx: &CanvasRenderingContext2d,
+    ) {
+        ctx.set_stroke_style_str(&self.styles.debug);
+        ctx.set_line_width(self.dpr);
 
-            p.x += dx * size;
-            p.y += dy * size;
+        let length =
+            self.sim.unit_size * FIELD_ARROW_LENGTH;
+        let head =
+            self.sim.unit_size * FIELD_ARROW_HEAD;
 
-            if p.x < -size {
-                p.x = w + size;
-            }
-            if p.y < -size {
-                p.y = h + size;
-            }
-            if p.x > w + size {
-                p.x = -size;
-            }
-            if p.y > h + size {
-                p.y = -size;
+        ctx.begin_path();
+        for i in 0..self.num_of_horizontal_grids {
+            let y = i as f64 * self.unit_size_y;
+            for j in 0..self.num_of_vertical_grids {
+                let x = j as f64 * self.unit_size_x;
+                let angle =
+                    self.sim.field_angle(x, y);
+                let (tip_x, tip_y) = (
+                    x + length * angle.cos(),
+                    y + length * angle.sin(),
+                );
+                ctx.move_to(x, y);
+                ctx.line_to(tip_x, tip_y);
+                for side in [-1.0, 1.0] {
+                    let a =
+                        angle + PI + side * PI / 6.0;
+                    ctx.move_to(tip_x, tip_y);
+                    ctx.line_to(
+                        tip_x + head * a.cos(),
+                        tip_y + head * a.sin(),
+                    );
+                }
             }
         }
+        ctx.stroke();
     }
 
-    // Repeatedly called from 'Proxy.run'.
-    pub fn draw(&mut self) {
-        self.ctx.set_fill_style(
-            &self.bgcolor.as_str().into(),
-        );
-        self.ctx.fill_rect(
-            0_f64,
-            0_f64,
-            self.width,
-            self.height,
-        );
-
+    fn render_sticks(
+        &self,
+        ctx: &CanvasRenderingContext2d,
+    ) {
         // ------------------------------------
         // Sticks
         // ------------------------------------
@@ -305,13 +1943,55 @@ impl Canvas {
         // For smoother animations, we are
         // taking 2 particles to interporate
         // the average for these 2 particles.
-        self.ctx.set_stroke_style(
-            &self.color2.as_str().into(),
+        ctx.set_stroke_style_str(&self.color2);
+        ctx.set_line_width(
+            self.stick_width * self.dpr,
         );
-        self.ctx.set_line_width(1.0);
 
-        let ripple_effect_range_max =
-            8.0 * self.unit_size;
+        ctx.set_line_cap(self.stick_cap.as_str());
+
+        let min_length = self.stick_min_length;
+        let max_length = self
+            .stick_max_length
+            .unwrap_or(self.sim.unit_size)
+            .max(min_length);
+
+        let ripple_effect_range_max = self
+            .ripple_range_units
+            * self.sim.unit_size;
+        let wrap_size = self.wrap_size();
+
+        // Only needed for counting particles
+        // around each stick.
+        let grid = match self.stick_mode {
+            StickMode::Density => {
+                Some(SpatialGrid::build(
+                    self.sim
+                        .particles
+                        .iter()
+                        .map(|p| (p.x, p.y)),
+                    self.sim.width,
+                    self.sim.height,
+                    ripple_effect_range_max,
+                ))
+            }
+            StickMode::Proximity => None,
+        };
+
+        // For finding the closest particles.
+        // Looking through all particles for
+        // every stick was the slowest part.
+        let closest_grid = SpatialGrid::build(
+            self.sim
+                .particles
+                .iter()
+                .map(|p| (p.x, p.y)),
+            self.sim.width,
+            self.sim.height,
+            self.sim.unit_size,
+        );
+        let max_radius =
+            self.sim.width.hypot(self.sim.height);
 
         // Tried using 'KdTree' hoping to improve
         // performance, but it became rather
@@ -320,60 +2000,33 @@ impl Canvas {
         // mosaikekkan
         // let mut tree = KdTree::new(2);
         // for (index, particle) in
-        //     self.particles.iter().enumerate()
+        //     self.sim.particles.iter().enumerate()
         // {
         //     tree.add([particle.x, particle.y], index)
         //         .unwrap();
         // }
 
         for i in 0..self.num_of_horizontal_grids {
-            let y = i as f64 * self.unit_size;
+            let y = i as f64 * self.unit_size_y;
             for j in 0..self.num_of_vertical_grids {
-                let x = j as f64 * self.unit_size;
+                let x = j as f64 * self.unit_size_x;
 
                 // Find the two closest particles to the stick.
-                let mut closest_part = [
-                    Rc::new(RefCell::new(
-                        &self.particles[0],
-                    )),
-                    Rc::new(RefCell::new(
-                        &self.particles[1],
-                    )),
-                ];
-                let mut closest_dist =
-                    [f64::MAX, f64::MAX];
-
-                // mosaikekkan
-                // let indices = tree
-                //     .within(
-                //         &[x, y],
-                //         ripple_effect_range_max
-                //             * ripple_effect_range_max,
-                //         &squared_euclidean,
-                //     )
-                //     .unwrap();
-
-                // for (_, &index) in indices {
-                for p in &self.particles {
-                    // let p = &self.particles[index];
-                    let dist = ((p.x - x).powi(2)
-                        + (p.y - y).powi(2))
-                    .sqrt();
-
-                    if dist < closest_dist[0] {
-                        closest_dist[1] =
-                            closest_dist[0];
-                        closest_part[1] =
-                            closest_part[0].clone();
-                        closest_dist[0] = dist;
-                        closest_part[0] =
-                            Rc::new(RefCell::new(p));
-                    } else if dist < closest_dist[1] {
-                        closest_dist[1] = dist;
-                        closest_part[1] =
-                            Rc::new(RefCell::new(p));
-                    }
-                }
+                // Without particles, there is nothing
+                // to take angles from, and we draw
+                // no sticks.
+                let (closest_part, closest_dist) =
+                    match closest_two_in_grid(
+                        &self.sim.particles,
+                        &closest_grid,
+                        x,
+                        y,
+                        max_radius,
+                        wrap_size,
+                    ) {
+                        Some(closest) => closest,
+                        None => continue,
+                    };
 
                 // If we were to just use the angle
                 // of the closest particle, the animation
@@ -398,100 +2051,909 @@ impl Canvas {
                 let total_dist =
                     closest_dist[0] + closest_dist[1];
 
-                if total_dist > 0.0 {
-                    let weight_0 =
-                        closest_dist[1] / total_dist;
-                    let weight_1 = 1.0 - weight_0;
-                    let part_0 =
-                        closest_part[0].borrow();
-                    let part_1 =
-                        closest_part[1].borrow();
-                    angle = part_0.angle * weight_0
-                        + part_1.angle * weight_1;
+                if self.stick_neighbors == 1 {
+                    angle = closest_part[0].angle;
+                } else if self.stick_neighbors > 2 {
+                    // Same as below, but for more
+                    // particles. Weights are inverse
+                    // of the distances.
+                    angle = weighted_angle(
+                        &closest_k_in_grid(
+                            &self.sim.particles,
+                            &closest_grid,
+                            x,
+                            y,
+                            self.stick_neighbors,
+                            max_radius,
+                            wrap_size,
+                        ),
+                    );
+                } else if total_dist > 0.0 {
+                    // Along the shorter arc, or sticks
+                    // would briefly flip when angles
+                    // are on both sides of 0.
+                    let weight_1 =
+                        closest_dist[0] / total_dist;
+                    angle = lerp_angle(
+                        closest_part[0].angle,
+                        closest_part[1].angle,
+                        weight_1,
+                    );
                 }
 
                 // If the closest distance to particles
-                // is more than 8 units away, we want
+                // is more than 'ripple_range_units'
+                // (8 by default) away, we want
                 // the length of the stick to be fixed
-                // to 2px. If not, then have
+                // to 'stick_min_length'. If not, then have
                 // a proportional size; closer to
                 // the particles, bigger it gets.
-                let dist_ratio = total_dist
-                    / ripple_effect_range_max;
+                let dist_ratio = self
+                    .stick_falloff
+                    .apply(
+                    (total_dist
+                        / ripple_effect_range_max)
+                        .clamp(0.0, 1.0),
+                );
 
-                let stick_size = self
-                    .unit_size
-                    .lerp(2.0, dist_ratio)
-                    .max(2.0)
-                    .min(self.unit_size);
+                let stick_size = match &grid {
+                    // More particles within the range,
+                    // longer the stick gets.
+                    Some(grid) => {
+                        let count = grid
+                            .nearby(
+                                x,
+                                y,
+                                ripple_effect_range_max,
+                                wrap_size,
+                            )
+                            .into_iter()
+                            .filter(|&index| {
+                                let p = &self.sim.particles[index];
+                                measure(p, x, y, wrap_size)
+                                    < ripple_effect_range_max
+                            })
+                            .count();
+                        let density = (count as f64
+                            / DENSITY_SATURATION)
+                            .min(1.0);
+                        min_length
+                            .lerp(max_length, density)
+                            .max(min_length)
+                            .min(max_length)
+                    }
+                    None => max_length
+                        .lerp(min_length, dist_ratio)
+                        .max(min_length)
+                        .min(max_length),
+                };
 
-                self.ctx.save();
-                self.ctx
-                    .translate(x, y)
-                    .unwrap_or(());
-                self.ctx.rotate(angle).unwrap_or(());
-                self.ctx.begin_path();
-                self.ctx.move_to(0_f64, 0_f64);
-                self.ctx.line_to(stick_size, 0_f64);
-                self.ctx.stroke();
-                self.ctx.restore();
+                ctx.save();
+                ctx.translate(x, y).unwrap_or(());
+                ctx.rotate(angle).unwrap_or(());
+                ctx.begin_path();
+                ctx.move_to(0_f64, 0_f64);
+                if self.curved_sticks {
+                    // Starts in 'angle', and ends
+                    // in the flow's direction at
+                    // the tip (as if it was straight).
+                    let tip_angle =
+                        self.sim.field_angle(
+                            x + stick_size
+                                * angle.cos(),
+                            y + stick_size
+                                * angle.sin(),
+                        );
+                    let bend =
+                        angle_diff(angle, tip_angle)
+                            .clamp(
+                                -MAX_STICK_BEND,
+                                MAX_STICK_BEND,
+                            );
+                    let half = stick_size / 2.0;
+                    ctx.quadratic_curve_to(
+                        half,
+                        0_f64,
+                        half + half * bend.cos(),
+                        half * bend.sin(),
+                    );
+                } else {
+                    ctx.line_to(stick_size, 0_f64);
+                }
+                ctx.stroke();
+                ctx.restore();
             }
         }
 
-        // ------------------------------------
-        // Particles
-        // ------------------------------------
-        self.ctx.set_fill_style(
-            &self.color.as_str().into(),
+        // Back to the default so that
+        // the rest won't be affected.
+        ctx.set_line_cap(LineCap::Butt.as_str());
+    }
+}
+
+// The two particles closest to (x, y),
+// and their distances. With 'wrap' (the size
+// of the canvas), distances are measured
+// across the edges as well.
+// With only one particle, both are the same one.
+// With no particles, returns 'None'.
+fn closest_two(
+    particles: &[Particle],
+    x: f64,
+    y: f64,
+    wrap: Option<(f64, f64)>,
+) -> Option<([&Particle; 2], [f64; 2])> {
+    let first = particles.first()?;
+    let mut closest_part = [first, first];
+    let mut closest_dist = [f64::MAX, f64::MAX];
+
+    // mosaikekkan
+    // let indices = tree
+    //     .within(
+    //         &[x, y],
+    //         ripple_effect_range_max
+    //             * ripple_effect_range_max,
+    //         &squared_euclidean,
+    //     )
+    //     .unwrap();
+
+    // for (_, &index) in indices {
+    for p in particles {
+        // let p = &self.particles[index];
+        let dist = measure(p, x, y, wrap);
+        keep_closest(
+            &mut closest_part,
+            &mut closest_dist,
+            p,
+            dist,
         );
+    }
 
-        let radius = self.particle_size / 2.0;
+    if particles.len() == 1 {
+        closest_dist[1] = closest_dist[0];
+    }
 
-        for p in &self.particles {
-            // Translate the canvas to the particle position.
-            self.ctx.save();
-            self.ctx
-                .translate(p.x, p.y)
-                .unwrap_or(());
+    Some((closest_part, closest_dist))
+}
 
-            // Rotate the canvas based on the particle angle.
-            self.ctx.rotate(p.angle).unwrap_or(());
+// Same as 'closest_two', but only looks at
+// nearby cells of 'grid' (built from
+// 'particles'), and widens the range until
+// it finds two particles within it. Falls
+// back to 'closest_two' beyond 'max_radius'.
+fn closest_two_in_grid<'a>(
+    particles: &'a [Particle],
+    grid: &SpatialGrid,
+    x: f64,
+    y: f64,
+    max_radius: f64,
+    wrap: Option<(f64, f64)>,
+) -> Option<([&'a Particle; 2], [f64; 2])> {
+    if particles.len() < 2 {
+        return closest_two(particles, x, y, wrap);
+    }
+    let mut radius = grid.cell_size();
+    while radius < max_radius {
+        let candidates =
+            grid.nearby(x, y, radius, wrap);
+        let mut closest_part = [&particles[0]; 2];
+        let mut closest_dist = [f64::MAX; 2];
+        for i in candidates {
+            let p = &particles[i];
+            keep_closest(
+                &mut closest_part,
+                &mut closest_dist,
+                p,
+                measure(p, x, y, wrap),
+            );
+        }
+        // Anything closer would have been
+        // in the cells we looked at.
+        if closest_dist[1] <= radius {
+            return Some((
+                closest_part,
+                closest_dist,
+            ));
+        }
+        radius *= 2.0;
+    }
+    closest_two(particles, x, y, wrap)
+}
 
-            self.ctx.begin_path();
-            self.ctx
-                .arc(
-                    0_f64,
-                    0_f64,
-                    radius,
-                    0_f64,
-                    2.0 * PI,
-                )
-                .unwrap_or(());
-            self.ctx.fill();
+// Puts 'p' in the two closest (closest first)
+// if it is closer than either.
+fn keep_closest<'a>(
+    closest_part: &mut [&'a Particle; 2],
+    closest_dist: &mut [f64; 2],
+    p: &'a Particle,
+    dist: f64,
+) {
+    if dist < closest_dist[0] {
+        closest_dist[1] = closest_dist[0];
+        closest_part[1] = closest_part[0];
+        closest_dist[0] = dist;
+        closest_part[0] = p;
+    } else if dist < closest_dist[1] {
+        closest_dist[1] = dist;
+        closest_part[1] = p;
+    }
+}
+
+// Up to 'k' particles closest to (x, y),
+// and their distances (closest first).
+fn closest_k(
+    particles: &[Particle],
+    x: f64,
+    y: f64,
+    k: usize,
+    wrap: Option<(f64, f64)>,
+) -> Vec<(&Particle, f64)> {
+    let k = k.min(particles.len());
+    let mut closest: Vec<(&Particle, f64)> =
+        Vec::with_capacity(k + 1);
+    if k == 0 {
+        return closest;
+    }
+    for p in particles {
+        keep_k(
+            &mut closest,
+            k,
+            p,
+            measure(p, x, y, wrap),
+        );
+    }
+    closest
+}
+
+// Same as 'closest_k', but only looks at
+// nearby cells of 'grid' as in
+// 'closest_two_in_grid'.
+fn closest_k_in_grid<'a>(
+    particles: &'a [Particle],
+    grid: &SpatialGrid,
+    x: f64,
+    y: f64,
+    k: usize,
+    max_radius: f64,
+    wrap: Option<(f64, f64)>,
+) -> Vec<(&'a Particle, f64)> {
+    let k = k.min(particles.len());
+    let mut radius = grid.cell_size();
+    while k > 0 && radius < max_radius {
+        let mut closest = Vec::with_capacity(k + 1);
+        for i in grid.nearby(x, y, radius, wrap) {
+            let p = &particles[i];
+            keep_k(
+                &mut closest,
+                k,
+                p,
+                measure(p, x, y, wrap),
+            );
+        }
+        if closest.len() == k
+            && closest[k - 1].1 <= radius
+        {
+            return closest;
+        }
+        radius *= 2.0;
+    }
+    closest_k(particles, x, y, k, wrap)
+}
+
+// Puts 'p' in 'closest' (sorted, at most 'k')
+// if it is closer than any of them.
+fn keep_k<'a>(
+    closest: &mut Vec<(&'a Particle, f64)>,
+    k: usize,
+    p: &'a Particle,
+    dist: f64,
+) {
+    if closest.len() < k || dist < closest[k - 1].1 {
+        let index = closest
+            .partition_point(|&(_, d)| d <= dist);
+        closest.insert(index, (p, dist));
+        closest.truncate(k);
+    }
+}
+
+// From a particle to (x, y) for 'closest_*'.
+fn measure(
+    p: &Particle,
+    x: f64,
+    y: f64,
+    wrap: Option<(f64, f64)>,
+) -> f64 {
+    match wrap {
+        Some((w, h)) => {
+            toroidal_distance(p.x, p.y, x, y, w, h)
+        }
+        None => distance((p.x, p.y), (x, y)),
+    }
+}
 
-            self.ctx.restore();
+// Inverse distance weighted average of angles.
+// A particle right on the spot wins.
+// Averaging unit vectors (instead of radians)
+// so that angles around 0 and 2PI agree.
+fn weighted_angle(
+    neighbors: &[(&Particle, f64)],
+) -> f64 {
+    let (mut sin_sum, mut cos_sum) = (0.0, 0.0);
+    for &(p, dist) in neighbors {
+        if dist <= f64::EPSILON {
+            return p.angle;
         }
+        sin_sum += p.angle.sin() / dist;
+        cos_sum += p.angle.cos() / dist;
     }
+    sin_sum.atan2(cos_sum)
 }
 
-fn generate_particles(
+// Pairs of particle indices (each pair once)
+// which are closer than 'max_dist', and
+// their distances. Only looks at particles
+// in nearby cells of the grid. With 'wraps',
+// measured across the edges too.
+fn close_pairs(
+    particles: &[Particle],
     width: f64,
     height: f64,
-    count: usize,
-) -> Vec<Particle> {
-    let mut rng = rand::thread_rng();
-    let mut particles = Vec::new();
+    max_dist: f64,
+    wraps: bool,
+) -> Vec<(usize, usize, f64)> {
+    let wrap = if wraps {
+        Some((width, height))
+    } else {
+        None
+    };
+    let grid = SpatialGrid::build(
+        particles.iter().map(|p| (p.x, p.y)),
+        width,
+        height,
+        max_dist,
+    );
+    let mut pairs = Vec::new();
+    for (i, a) in particles.iter().enumerate() {
+        for j in grid.nearby(a.x, a.y, max_dist, wrap)
+        {
+            if j <= i {
+                continue;
+            }
+            let dist = measure(
+                &particles[j],
+                a.x,
+                a.y,
+                wrap,
+            );
+            if dist < max_dist {
+                pairs.push((i, j, dist));
+            }
+        }
+    }
+    pairs
+}
+
+// Fill style for the background, or 'None'
+// when it is transparent (and the canvas
+// should be cleared instead).
+// For trails, we paint the background
+// semi-transparent so that previous
+// frames remain partially visible.
+fn background_fill(
+    bgcolor: &str,
+    trail_alpha: f64,
+) -> Option<String> {
+    if is_transparent(bgcolor) {
+        None
+    } else if trail_alpha > 0.0 {
+        Some(
+            hex_to_rgba_string(
+                bgcolor,
+                1.0 - trail_alpha,
+            )
+            .unwrap_or_else(|_| bgcolor.to_string()),
+        )
+    } else {
+        Some(bgcolor.to_string())
+    }
+}
+
+// When 'color' is not a valid hex code
+// (ex. CSS color names), we use it as is.
+fn derive_color2(
+    color: &str,
+    intensity: f64,
+) -> String {
+    color_change_intensity_hex(color, intensity)
+        .unwrap_or_else(|_| color.to_string())
+}
+
+// Not below 1.0 (which would blur even
+// on normal screens).
+fn capped_dpr(dpr: f64, max_dpr: Option<f64>) -> f64 {
+    match max_dpr {
+        Some(max) => dpr.min(max.max(1.0)),
+        None => dpr,
+    }
+}
+
+// At least 1 column. Falls back to 'default'
+// for NaN or infinity.
+fn valid_grid_size(v: f64, default: f64) -> f64 {
+    if v.is_finite() {
+        v.max(1.0)
+    } else {
+        default
+    }
+}
+
+// In device pixels.
+fn buffer_size(
+    css_width: f64,
+    css_height: f64,
+    dpr: f64,
+) -> (u32, u32) {
+    (
+        (css_width * dpr) as u32,
+        (css_height * dpr) as u32,
+    )
+}
+
+// Colors from 'from' to 'to' (inclusive).
+// When either is not a valid hex code,
+// we just use 'from'.
+fn gradient_steps(
+    from: &str,
+    to: &str,
+    steps: usize,
+) -> Vec<String> {
+    match (hex_to_rgb(from), hex_to_rgb(to)) {
+        (Ok(a), Ok(b)) => (0..steps)
+            .map(|i| {
+                let t = i as f64 / (steps - 1) as f64;
+                rgb_to_hex(&lerp_rgb(t, &a, &b))
+            })
+            .collect(),
+        _ => vec![from.to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particle(
+        x: f64,
+        y: f64,
+        angle: f64,
+    ) -> Particle {
+        Particle::new(x, y, angle)
+    }
+
+    #[test]
+    fn close_pairs_within_distance() {
+        let particles = [
+            particle(0.0, 0.0, 0.0),
+            particle(3.0, 4.0, 0.0),
+            particle(50.0, 50.0, 0.0),
+            particle(53.0, 50.0, 0.0),
+        ];
+        let mut pairs = close_pairs(
+            &particles, 100.0, 100.0, 6.0, false,
+        );
+        pairs.sort_by_key(|&(i, j, _)| (i, j));
+        assert_eq!(
+            pairs,
+            vec![(0, 1, 5.0), (2, 3, 3.0)]
+        );
+    }
+
+    #[test]
+    fn close_pairs_across_wrapped_edges() {
+        let particles = vec![
+            particle(1.0, 50.0, 0.0),
+            particle(98.0, 50.0, 0.0),
+            particle(50.0, 2.0, 0.0),
+        ];
+        assert!(close_pairs(
+            &particles, 100.0, 100.0, 6.0, false,
+        )
+        .is_empty());
+        assert_eq!(
+            close_pairs(
+                &particles, 100.0, 100.0, 6.0, true,
+            ),
+            vec![(0, 1, 3.0)]
+        );
+    }
+
+    #[test]
+    fn resolution_scale_within_range() {
+        let scale = |v: f64| {
+            CanvasBuilder::new(
+                "#000000".into(),
+                "#ffffff".into(),
+            )
+            .resolution_scale(v)
+            .resolution_scale
+        };
+        assert_eq!(scale(0.5), 0.5);
+        assert_eq!(scale(0.0), MIN_RESOLUTION_SCALE);
+        assert_eq!(scale(2.0), 1.0);
+        assert_eq!(scale(f64::NAN), 1.0);
+        assert_eq!(scale(f64::INFINITY), 1.0);
+    }
+
+    #[test]
+    fn grid_size_at_least_one() {
+        assert_eq!(valid_grid_size(20.0, 30.0), 20.0);
+        assert_eq!(valid_grid_size(0.0, 30.0), 1.0);
+        assert_eq!(valid_grid_size(-5.0, 30.0), 1.0);
+        assert_eq!(
+            valid_grid_size(f64::NAN, 30.0),
+            30.0
+        );
+        assert_eq!(
+            valid_grid_size(f64::INFINITY, 30.0),
+            30.0
+        );
+    }
+
+    #[test]
+    fn buffer_size_respects_max_dpr() {
+        assert_eq!(
+            buffer_size(
+                400.0,
+                800.0,
+                capped_dpr(4.0, Some(2.0)),
+            ),
+            (800, 1600)
+        );
+        assert_eq!(
+            buffer_size(
+                400.0,
+                800.0,
+                capped_dpr(4.0, None)
+            ),
+            (1600, 3200)
+        );
+        // Lower ratios are left as they are.
+        assert_eq!(capped_dpr(1.5, Some(2.0)), 1.5);
+    }
 
-    let x_range = Uniform::new(0.0, width);
-    let y_range = Uniform::new(0.0, height);
-    let angle_range = Uniform::new(0.0, 2.0 * PI);
+    #[test]
+    fn closest_two_without_particles() {
+        assert!(closest_two(&[], 0.0, 0.0, None)
+            .is_none());
+    }
+
+    #[test]
+    fn closest_two_with_one_particle() {
+        let particles = [particle(3.0, 4.0, 1.0)];
+        let (parts, dists) =
+            closest_two(&particles, 0.0, 0.0, None)
+                .unwrap();
+        assert_eq!(parts[0].angle, 1.0);
+        assert_eq!(parts[1].angle, 1.0);
+        assert_eq!(dists, [5.0, 5.0]);
+    }
+
+    #[test]
+    fn nearest_particle_is_the_closest() {
+        let particles = [
+            particle(10.0, 0.0, 1.0),
+            particle(1.0, 0.0, 2.0),
+        ];
+        let (parts, _) =
+            closest_two(&particles, 8.0, 0.0, None)
+                .unwrap();
+        assert_eq!(parts[0].angle, 1.0);
+    }
+
+    #[test]
+    fn closest_two_across_edges() {
+        let particles = [
+            particle(50.0, 50.0, 1.0),
+            particle(99.0, 50.0, 2.0),
+        ];
+        let (parts, dists) = closest_two(
+            &particles,
+            1.0,
+            50.0,
+            Some((100.0, 100.0)),
+        )
+        .unwrap();
+        assert_eq!(parts[0].angle, 2.0);
+        assert!((dists[0] - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn closest_k_is_sorted_and_capped() {
+        let particles = [
+            particle(10.0, 0.0, 1.0),
+            particle(1.0, 0.0, 2.0),
+            particle(3.0, 0.0, 3.0),
+            particle(2.0, 0.0, 4.0),
+        ];
+        let closest =
+            closest_k(&particles, 0.0, 0.0, 3, None);
+        let angles: Vec<f64> = closest
+            .iter()
+            .map(|(p, _)| p.angle)
+            .collect();
+        assert_eq!(angles, vec![2.0, 4.0, 3.0]);
+
+        // More than we have.
+        assert_eq!(
+            closest_k(&particles, 0.0, 0.0, 10, None)
+                .len(),
+            4
+        );
+        assert!(closest_k(&[], 0.0, 0.0, 3, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn closest_k_in_grid_matches_naive() {
+        let mut sim = Simulation::new(Some(1), 200);
+        sim.resize(400.0, 300.0);
+        let grid = SpatialGrid::build(
+            sim.particles.iter().map(|p| (p.x, p.y)),
+            sim.width,
+            sim.height,
+            20.0,
+        );
+        let max_radius = sim.width.hypot(sim.height);
+        let dists = |found: Vec<(&Particle, f64)>| {
+            found
+                .iter()
+                .map(|&(_, d)| d)
+                .collect::<Vec<f64>>()
+        };
+        for &wrap in &[None, Some((400.0, 300.0))] {
+            for &(x, y) in &[
+                (0.0, 0.0),
+                (200.0, 150.0),
+                (390.0, 5.0),
+            ] {
+                assert_eq!(
+                    dists(closest_k_in_grid(
+                        &sim.particles,
+                        &grid,
+                        x,
+                        y,
+                        5,
+                        max_radius,
+                        wrap,
+                    )),
+                    dists(closest_k(
+                        &sim.particles,
+                        x,
+                        y,
+                        5,
+                        wrap,
+                    )),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_angle_by_inverse_distance() {
+        let (a, b) = (
+            particle(1.0, 0.0, 1.0),
+            particle(3.0, 0.0, 2.0),
+        );
+        // Equal weights.
+        let angle =
+            weighted_angle(&[(&a, 1.0), (&b, 1.0)]);
+        assert!((angle - 1.5).abs() < 1e-9);
+
+        // Closer one pulls harder.
+        let angle =
+            weighted_angle(&[(&a, 1.0), (&b, 3.0)]);
+        assert!(angle > 1.0 && angle < 1.5);
+
+        // Across 0/2PI.
+        let (c, d) = (
+            particle(0.0, 0.0, 0.1),
+            particle(0.0, 0.0, 6.2),
+        );
+        let angle =
+            weighted_angle(&[(&c, 1.0), (&d, 1.0)]);
+        assert!(angle.abs() < 0.1);
+        assert_eq!(
+            weighted_angle(&[(&b, 0.0), (&a, 1.0)]),
+            2.0
+        );
+    }
+
+    #[test]
+    fn transparent_background_is_cleared() {
+        assert_eq!(
+            background_fill("transparent", 0.0),
+            None
+        );
+        assert_eq!(background_fill("", 0.0), None);
+        // Not parsed as hex even with trails.
+        assert_eq!(
+            background_fill("Transparent", 0.5),
+            None
+        );
+
+        assert_eq!(
+            background_fill("#000", 0.0).as_deref(),
+            Some("#000")
+        );
+        assert_eq!(
+            background_fill("#000000", 0.5)
+                .as_deref(),
+            Some("rgba(0, 0, 0, 0.5)")
+        );
+    }
+
+    #[test]
+    fn falloff_curves_keep_endpoints() {
+        for curve in [
+            FalloffCurve::Linear,
+            FalloffCurve::Quadratic,
+            FalloffCurve::Ease,
+        ] {
+            assert_eq!(curve.apply(0.0), 0.0);
+            assert_eq!(curve.apply(1.0), 1.0);
+        }
+        assert_eq!(
+            FalloffCurve::Quadratic.apply(0.5),
+            0.25
+        );
+    }
+
+    #[test]
+    fn speed_ramp_settles_on_target() {
+        let mut ramp = SpeedRamp {
+            from: 1.0,
+            to: 3.0,
+            frames: 4,
+            progress: 0,
+            easing: Easing::InOutQuad,
+        };
+        assert_eq!(ramp.speed(), 1.0);
+        ramp.progress = 2;
+        assert_eq!(ramp.speed(), 2.0);
+        ramp.progress = 4;
+        assert!(ramp.is_done());
+        assert_eq!(ramp.speed(), 3.0);
+    }
+
+    #[test]
+    fn color_transition_ends_at_target() {
+        let mut transition = ColorTransition {
+            from_bgcolor: "#000000".into(),
+            from_color: "#ffffff".into(),
+            to_bgcolor: "#ffffff".into(),
+            to_color: "#000000".into(),
+            frames: 4,
+            progress: 0,
+            easing: Easing::InOutQuad,
+        };
+        assert_eq!(
+            transition.colors(),
+            ("#000000".into(), "#ffffff".into())
+        );
+        transition.progress = 2;
+        assert_eq!(transition.colors().0, "#808080");
+        assert!(!transition.is_done());
+        transition.progress = 4;
+        assert!(transition.is_done());
+        assert_eq!(
+            transition.colors(),
+            ("#ffffff".into(), "#000000".into())
+        );
+    }
+
+    #[test]
+    fn closest_two_picks_nearest() {
+        let particles = [
+            particle(10.0, 0.0, 1.0),
+            particle(1.0, 0.0, 2.0),
+            particle(2.0, 0.0, 3.0),
+        ];
+        let (parts, dists) =
+            closest_two(&particles, 0.0, 0.0, None)
+                .unwrap();
+        assert_eq!(
+            [parts[0].angle, parts[1].angle],
+            [2.0, 3.0]
+        );
+        assert_eq!(dists, [1.0, 2.0]);
+    }
 
-    for _ in 0..count {
-        let x = rng.sample(x_range);
-        let y = rng.sample(y_range);
-        let angle = rng.sample(angle_range);
-        particles.push(Particle { x, y, angle });
+    // Distances to the two closest particles
+    // for every stick, like 'render_sticks'.
+    fn stick_distances<F>(
+        sim: &Simulation,
+        unit_size: f64,
+        mut search: F,
+    ) -> Vec<[f64; 2]>
+    where
+        F: FnMut(f64, f64) -> [f64; 2],
+    {
+        let cols = (sim.width / unit_size) as usize;
+        let rows = (sim.height / unit_size) as usize;
+        let mut dists =
+            Vec::with_capacity(cols * rows);
+        for i in 0..rows {
+            for j in 0..cols {
+                dists.push(search(
+                    j as f64 * unit_size,
+                    i as f64 * unit_size,
+                ));
+            }
+        }
+        dists
+    }
+
+    fn check_closest_two_in_grid(
+        num_of_particles: usize,
+        wraps: bool,
+    ) {
+        let unit_size = 20.0;
+        let mut sim = Simulation::new(
+            Some(1),
+            num_of_particles,
+        );
+        sim.unit_size = unit_size;
+        sim.resize(1200.0, 800.0);
+        let max_radius = sim.width.hypot(sim.height);
+        let wrap = if wraps {
+            Some((sim.width, sim.height))
+        } else {
+            None
+        };
+
+        let naive = stick_distances(
+            &sim,
+            unit_size,
+            |x, y| {
+                closest_two(
+                    &sim.particles,
+                    x,
+                    y,
+                    wrap,
+                )
+                .unwrap()
+                .1
+            },
+        );
+        let grid = SpatialGrid::build(
+            sim.particles.iter().map(|p| (p.x, p.y)),
+            sim.width,
+            sim.height,
+            unit_size,
+        );
+        let gridded = stick_distances(
+            &sim,
+            unit_size,
+            |x, y| {
+                closest_two_in_grid(
+                    &sim.particles,
+                    &grid,
+                    x,
+                    y,
+                    max_radius,
+                    wrap,
+                )
+                .unwrap()
+                .1
+            },
+        );
+        assert_eq!(naive, gridded);
     }
 
-    particles
+    #[test]
+    fn grid_search_matches_naive() {
+        check_closest_two_in_grid(50, false);
+        check_closest_two_in_grid(50, true);
+        check_closest_two_in_grid(500, true);
+    }
 }
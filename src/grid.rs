@@ -0,0 +1,225 @@
+/// Buckets particle indices into square cells
+/// so that we only need to look at particles
+/// in nearby cells when searching around a point,
+/// instead of going through all the particles.
+/// It is rebuilt every frame since particles move.
+#[derive(Debug, Clone)]
+pub struct SpatialGrid {
+    cell_size: f64,
+    cols: usize,
+    rows: usize,
+    cells: Vec<Vec<usize>>,
+}
+
+impl SpatialGrid {
+    pub fn new(
+        width: f64,
+        height: f64,
+        cell_size: f64,
+    ) -> Self {
+        let cell_size = cell_size.max(1.0);
+        let cols = ((width / cell_size).ceil()
+            as usize)
+            .max(1);
+        let rows = ((height / cell_size).ceil()
+            as usize)
+            .max(1);
+        SpatialGrid {
+            cell_size,
+            cols,
+            rows,
+            cells: vec![Vec::new(); cols * rows],
+        }
+    }
+
+    /// Builds a grid from points where
+    /// the index of each point is stored.
+    pub fn build<I>(
+        points: I,
+        width: f64,
+        height: f64,
+        cell_size: f64,
+    ) -> Self
+    where
+        I: IntoIterator<Item = (f64, f64)>,
+    {
+        let mut grid = SpatialGrid::new(
+            width, height, cell_size,
+        );
+        for (index, (x, y)) in
+            points.into_iter().enumerate()
+        {
+            grid.insert(index, x, y);
+        }
+        grid
+    }
+
+    // Particles slightly off the canvas
+    // (before they wrap) go to the edge cells.
+    fn cell_of(
+        &self,
+        x: f64,
+        y: f64,
+    ) -> (usize, usize) {
+        let col = (x / self.cell_size)
+            .floor()
+            .max(0.0) as usize;
+        let row = (y / self.cell_size)
+            .floor()
+            .max(0.0) as usize;
+        (
+            col.min(self.cols - 1),
+            row.min(self.rows - 1),
+        )
+    }
+
+    pub fn cell_size(&self) -> f64 {
+        self.cell_size
+    }
+
+    pub fn insert(
+        &mut self,
+        index: usize,
+        x: f64,
+        y: f64,
+    ) {
+        let (col, row) = self.cell_of(x, y);
+        self.cells[row * self.cols + col].push(index);
+    }
+
+    /// Indices of points which may be within
+    /// `radius` from `(x, y)`. Callers still
+    /// need to check the actual distance.
+    pub fn candidates(
+        &self,
+        x: f64,
+        y: f64,
+        radius: f64,
+    ) -> impl Iterator<Item = usize> + '_ {
+        let (col_0, row_0) =
+            self.cell_of(x - radius, y - radius);
+        let (col_1, row_1) =
+            self.cell_of(x + radius, y + radius);
+        (row_0..=row_1).flat_map(move |row| {
+            (col_0..=col_1).flat_map(move |col| {
+                self.cells[row * self.cols + col]
+                    .iter()
+                    .copied()
+            })
+        })
+    }
+}
+
+impl SpatialGrid {
+    /// Same as 'candidates', but also looks
+    /// across the edges (for particles which
+    /// wrap around) of 'width' x 'height'.
+    /// Each index comes only once.
+    pub fn wrapped_candidates(
+        &self,
+        x: f64,
+        y: f64,
+        radius: f64,
+        width: f64,
+        height: f64,
+    ) -> Vec<usize> {
+        let shifts = |v: f64, len: f64| {
+            let mut shifts = vec![0.0];
+            if v - radius < 0.0 {
+                shifts.push(len);
+            }
+            if v + radius > len {
+                shifts.push(-len);
+            }
+            shifts
+        };
+        let mut found = Vec::new();
+        for &dx in &shifts(x, width) {
+            for &dy in &shifts(y, height) {
+                found.extend(self.candidates(
+                    x + dx,
+                    y + dy,
+                    radius,
+                ));
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+
+    /// 'wrapped_candidates' when 'wrap' has
+    /// the size to wrap around, otherwise
+    /// 'candidates'.
+    pub fn nearby(
+        &self,
+        x: f64,
+        y: f64,
+        radius: f64,
+        wrap: Option<(f64, f64)>,
+    ) -> Vec<usize> {
+        match wrap {
+            Some((w, h)) => self.wrapped_candidates(
+                x, y, radius, w, h,
+            ),
+            None => self
+                .candidates(x, y, radius)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_include_nearby_points() {
+        let points = vec![
+            (5.0, 5.0),
+            (12.0, 8.0),
+            (95.0, 95.0),
+            (-3.0, 50.0),
+        ];
+        let grid = SpatialGrid::build(
+            points.clone(),
+            100.0,
+            100.0,
+            10.0,
+        );
+
+        let mut found: Vec<usize> =
+            grid.candidates(8.0, 8.0, 5.0).collect();
+        found.sort_unstable();
+        assert_eq!(found, vec![0, 1]);
+
+        let found: Vec<usize> = grid
+            .candidates(100.0, 100.0, 5.0)
+            .collect();
+        assert_eq!(found, vec![2]);
+
+        // Off-canvas points are in the edge cells.
+        let found: Vec<usize> =
+            grid.candidates(0.0, 50.0, 1.0).collect();
+        assert_eq!(found, vec![3]);
+    }
+
+    #[test]
+    fn wrapped_candidates_across_edges() {
+        let points = vec![(1.0, 50.0), (99.0, 50.0)];
+        let grid = SpatialGrid::build(
+            points, 100.0, 100.0, 10.0,
+        );
+        assert_eq!(
+            grid.candidates(1.0, 50.0, 3.0)
+                .collect::<Vec<_>>(),
+            vec![0]
+        );
+        assert_eq!(
+            grid.wrapped_candidates(
+                1.0, 50.0, 3.0, 100.0, 100.0
+            ),
+            vec![0, 1]
+        );
+    }
+}
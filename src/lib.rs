@@ -1,6 +1,8 @@
 pub mod app;
 pub mod canvas;
+pub mod grid;
 pub mod proxy;
+pub mod simulation;
 pub mod utils;
 
 use wasm_bindgen::prelude::*;
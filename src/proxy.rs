@@ -1,56 +1,740 @@
+use js_sys::{Function, Object, Reflect};
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
-use wasm_bindgen::JsValue;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_timer::Instant;
+use web_sys::{console, HtmlCanvasElement};
 
-use crate::canvas::Canvas;
+use crate::canvas::{
+    Canvas, CanvasBuilder, FalloffCurve, LineCap,
+    ParticleShape, StickMode,
+};
+use crate::simulation::{
+    EdgeBehavior, FlowMode, SpawnShape,
+};
 use crate::utils::{
-    get_canvas, request_animation_frame_future, timer,
+    get_canvas, request_animation_frame_future,
+    timer, Easing,
 };
 
-const REFRESH_RATE: i32 = 60;
+const DEFAULT_SELECTOR: &str = "#perlin-experiment";
+
+// Seconds. When a frame takes longer (ex. the tab
+// was in background), we pretend it didn't
+// so that particles won't jump.
+const MAX_DT: f64 = 0.1;
+
+// Number of frames for the rolling average of FPS.
+const FPS_WINDOW: usize = 60;
+
+// Keeps time deltas (msec) between frames
+// in a ring buffer.
+#[derive(Debug, Clone)]
+pub struct FrameTimer {
+    deltas: [f64; FPS_WINDOW],
+    index: usize,
+    count: usize,
+    last: Option<Instant>,
+}
+
+impl FrameTimer {
+    pub fn new() -> Self {
+        FrameTimer {
+            deltas: [0.0; FPS_WINDOW],
+            index: 0,
+            count: 0,
+            last: None,
+        }
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        if let Some(last) = self.last {
+            let delta = now
+                .duration_since(last)
+                .as_secs_f64()
+                * 1000.0;
+            self.deltas[self.index] = delta;
+            self.index =
+                (self.index + 1) % FPS_WINDOW;
+            self.count =
+                (self.count + 1).min(FPS_WINDOW);
+        }
+        self.last = Some(now);
+    }
+
+    // So that the next delta won't include
+    // the time we were paused.
+    pub fn skip(&mut self) {
+        self.last = None;
+    }
+
+    // Averaged over frames we have so far
+    // (until the window fills up).
+    // Returns 0 when no frames yet.
+    pub fn fps(&self) -> f64 {
+        let total: f64 =
+            self.deltas[..self.count].iter().sum();
+        if total > 0.0 {
+            self.count as f64 * 1000.0 / total
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Default for FrameTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
+    // Use different ones to have multiple
+    // instances on the same page.
+    #[serde(default = "default_selector")]
+    pub selector: String,
+    // "transparent" (or "") to show
+    // the page behind the canvas.
     pub bgcolor: String,
     pub color: String,
+    pub num_of_particles: Option<usize>,
+    // In frames. Old particles are respawned
+    // somewhere else. Omit it for forever.
+    pub particle_lifetime: Option<u32>,
+    // In pixels. Particles closer than this
+    // push each other away. Omit it to let
+    // them pile up.
+    pub separation_radius: Option<f64>,
+    // "rect" (default), "circle", or "ring"
+    pub spawn_shape: Option<SpawnShape>,
+    // Particles burst out from the center
+    // (also after resizing).
+    #[serde(default)]
+    pub intro_burst: bool,
+    pub color2_intensity: Option<f64>,
+    // Hex codes. Each particle gets one of them
+    // (instead of 'color').
+    pub palette: Option<Vec<String>>,
+    // Omit it for random animations.
+    pub seed: Option<u32>,
+    pub speed: Option<f64>,
+    pub mobile_breakpoint: Option<f64>,
+    pub particle_size_mobile: Option<f64>,
+    pub particle_size_desktop: Option<f64>,
+    pub grid_size_mobile: Option<f64>,
+    pub grid_size_desktop: Option<f64>,
+    // "wrap" (default), "bounce", or
+    // { "softbounds": { "margin": 40,
+    //   "strength": 1 } }
+    pub edge_behavior: Option<EdgeBehavior>,
+    // Pixels beyond the edges before wrapping.
+    // Defaults to the particle size.
+    pub wrap_margin: Option<f64>,
+    pub ripple_range_units: Option<f64>,
+    #[serde(default)]
+    pub double_buffer: bool,
+    pub trail_alpha: Option<f64>,
+    #[serde(default)]
+    pub velocity_coloring: bool,
+    // Defaults to true.
+    pub draw_sticks: Option<bool>,
+    pub draw_particles: Option<bool>,
+    // Draws particles as short lines along
+    // their movement.
+    #[serde(default)]
+    pub streaks: bool,
+    // Sticks bend along the flow.
+    #[serde(default)]
+    pub curved_sticks: bool,
+    // In pixels. Joins particles closer than
+    // this with lines.
+    pub link_distance: Option<f64>,
+    // Defaults to true (as browsers do).
+    pub image_smoothing: Option<bool>,
+    // Logs sizes to the console on resize
+    // (which we used to do by default).
+    #[serde(default)]
+    pub verbose: bool,
+    // Caps the device pixel ratio to keep
+    // buffers small on 3x or 4x screens.
+    pub max_dpr: Option<f64>,
+    // Defaults to true. When false, draws in
+    // CSS pixels (faster, but blurry on
+    // HiDPI screens).
+    pub use_dpr: Option<bool>,
+    // 1.0 (default) for full resolution, and
+    // ex. 0.5 for half (the browser scales it
+    // up). Try with 'image_smoothing' false.
+    pub resolution_scale: Option<f64>,
+    // Number of frames to fade in.
+    pub fade_in_frames: Option<u32>,
+    // "linear", "in_out_quad" (default),
+    // "in_quad", "out_quad", or "in_out_cubic".
+    pub easing: Option<Easing>,
+    // Throttles the animation down to the FPS.
+    // When omitted, it runs as fast as
+    // 'requestAnimationFrame' allows.
+    pub target_fps: Option<u32>,
+    // Waits at least this long (msec) between
+    // frames. 0 (default) for no waiting.
+    pub refresh_rate_ms: Option<u32>,
+    // "perlin" (default), "radial", or "spiral"
+    pub flow_mode: Option<FlowMode>,
+    pub noise_scale: Option<f64>,
+    pub time_scale: Option<f64>,
+    // Frames for the flow to change by 1.0
+    // on the time axis (100 by default).
+    pub time_divisor: Option<f64>,
+    pub jitter: Option<f64>,
+    pub angle_turns: Option<f64>,
+    // Layers of the noise for finer details
+    // (1 by default).
+    pub octaves: Option<u32>,
+    pub lacunarity: Option<f64>,
+    pub persistence: Option<f64>,
+    // [x, y] drift added to the flow.
+    pub wind: Option<(f64, f64)>,
+    // [min, max] of random opacity for
+    // particles (1.0 by default).
+    pub particle_alpha: Option<(f64, f64)>,
+    // Sizes vary within 1.0 +/- this times
+    // the particle size (0.0 by default).
+    pub size_jitter: Option<f64>,
+    // "proximity" (default) or "density"
+    pub stick_mode: Option<StickMode>,
+    // Sticks average angles of this many
+    // closest particles (2 by default).
+    pub stick_neighbors: Option<usize>,
+    // In pixels. 2 and the grid size by default.
+    pub stick_min_length: Option<f64>,
+    pub stick_max_length: Option<f64>,
+    // In CSS pixels (1 by default).
+    pub stick_width: Option<f64>,
+    // "butt" (default), "round", or "square"
+    pub stick_cap: Option<LineCap>,
+    // "linear" (default), "quadratic", or
+    // "ease"
+    pub stick_falloff: Option<FalloffCurve>,
+    // "circle" (default), "square", or
+    // "triangle"
+    pub particle_shape: Option<ParticleShape>,
+}
+
+fn default_selector() -> String {
+    DEFAULT_SELECTOR.to_string()
+}
+
+impl Config {
+    // Current options of the canvas, which
+    // can be passed to 'App' to set up
+    // the same (given 'seed').
+    // Those of 'Proxy' ('target_fps' and
+    // 'refresh_rate_ms') are left empty.
+    pub fn from_canvas(canvas: &Canvas) -> Config {
+        let sim = &canvas.sim;
+        let id = canvas.el.id();
+        Config {
+            selector: if id.is_empty() {
+                default_selector()
+            } else {
+                format!("#{}", id)
+            },
+            bgcolor: canvas.bgcolor.clone(),
+            color: canvas.color.clone(),
+            num_of_particles: Some(
+                sim.num_of_particles,
+            ),
+            particle_lifetime: sim.particle_lifetime,
+            separation_radius: sim.separation_radius,
+            spawn_shape: Some(sim.spawn_shape),
+            intro_burst: sim.intro_burst,
+            color2_intensity: Some(
+                canvas.color2_intensity,
+            ),
+            palette: Some(canvas.palette.clone())
+                .filter(|p| !p.is_empty()),
+            seed: sim.seed,
+            speed: Some(sim.speed),
+            mobile_breakpoint: Some(
+                canvas.mobile_breakpoint,
+            ),
+            particle_size_mobile: Some(
+                canvas.particle_size_mobile,
+            ),
+            particle_size_desktop: Some(
+                canvas.particle_size_desktop,
+            ),
+            grid_size_mobile: Some(
+                canvas.grid_size_mobile,
+            ),
+            grid_size_desktop: Some(
+                canvas.grid_size_desktop,
+            ),
+            edge_behavior: Some(sim.edge_behavior),
+            wrap_margin: sim.wrap_margin,
+            ripple_range_units: Some(
+                canvas.ripple_range_units,
+            ),
+            double_buffer: canvas.buffer.is_some(),
+            trail_alpha: Some(canvas.trail_alpha),
+            velocity_coloring: canvas
+                .velocity_coloring,
+            draw_sticks: Some(canvas.draw_sticks),
+            draw_particles: Some(
+                canvas.draw_particles,
+            ),
+            streaks: canvas.streaks,
+            curved_sticks: canvas.curved_sticks,
+            link_distance: canvas.link_distance,
+            image_smoothing: Some(
+                canvas.image_smoothing,
+            ),
+            verbose: canvas.verbose,
+            max_dpr: canvas.max_dpr,
+            use_dpr: Some(canvas.use_dpr),
+            resolution_scale: Some(
+                canvas.resolution_scale,
+            ),
+            fade_in_frames: Some(
+                canvas.fade_in_frames,
+            ),
+            easing: Some(canvas.easing),
+            target_fps: None,
+            refresh_rate_ms: None,
+            flow_mode: Some(sim.flow_mode),
+            noise_scale: Some(sim.noise_scale),
+            time_scale: Some(sim.time_scale),
+            time_divisor: Some(sim.time_divisor),
+            jitter: Some(sim.jitter),
+            angle_turns: Some(sim.angle_turns),
+            octaves: Some(sim.octaves),
+            lacunarity: Some(sim.lacunarity),
+            persistence: Some(sim.persistence),
+            wind: Some(sim.wind),
+            particle_alpha: Some(sim.alpha_range),
+            size_jitter: Some(sim.size_jitter),
+            stick_mode: Some(canvas.stick_mode),
+            stick_neighbors: Some(
+                canvas.stick_neighbors,
+            ),
+            stick_min_length: Some(
+                canvas.stick_min_length,
+            ),
+            stick_max_length: canvas.stick_max_length,
+            stick_width: Some(canvas.stick_width),
+            stick_cap: Some(canvas.stick_cap),
+            stick_falloff: Some(canvas.stick_falloff),
+            particle_shape: Some(
+                canvas.particle_shape,
+            ),
+        }
+    }
+}
+
+fn parse_config(
+    params: &JsValue,
+) -> Result<Config, JsValue> {
+    serde_wasm_bindgen::from_value(params.clone())
+        .map_err(|err| {
+            let message = match invalid_field(params)
+            {
+                Some(field) => format!(
+                    "Invalid config '{}': {}",
+                    field, err
+                ),
+                None => {
+                    format!("Invalid config: {}", err)
+                }
+            };
+            message.into()
+        })
+}
+
+// Serde tells us the field only when it is
+// missing. For wrong values, we try each
+// field alone (with the required ones)
+// to find which one fails.
+fn invalid_field(params: &JsValue) -> Option<String> {
+    let object = params.dyn_ref::<Object>()?;
+    Object::keys(object)
+        .iter()
+        .filter_map(|key| key.as_string())
+        .find(|key| {
+            let single = Object::new();
+            for (k, v) in [
+                ("bgcolor", JsValue::from("")),
+                ("color", JsValue::from("")),
+                (
+                    key.as_str(),
+                    Reflect::get(params, &key.into())
+                        .unwrap_or(
+                            JsValue::UNDEFINED,
+                        ),
+                ),
+            ] {
+                Reflect::set(&single, &k.into(), &v)
+                    .unwrap_or(false);
+            }
+            serde_wasm_bindgen::from_value::<Config>(
+                single.into(),
+            )
+            .is_err()
+        })
 }
 
 #[derive(Debug, Clone)]
 pub struct Proxy {
     pub canvas: Rc<RefCell<Canvas>>,
+    // Shared with clones of 'Proxy' so that
+    // the running loop sees the change.
+    pub paused: Rc<Cell<bool>>,
+    pub destroyed: Rc<Cell<bool>>,
+    // Whether the loop in 'run' is active.
+    pub running: Rc<Cell<bool>>,
+    pub frame_timer: Rc<RefCell<FrameTimer>>,
+    pub target_fps: Option<u32>,
+    // Shared so that we can change it
+    // while running.
+    pub refresh_rate_ms: Rc<Cell<u32>>,
+    // When we last updated the canvas.
+    pub last_update: Rc<Cell<Option<Instant>>>,
+    // Called with (frame, fps) after each
+    // frame drawn in 'run'.
+    pub on_frame: Rc<RefCell<Option<Function>>>,
 }
 
 #[allow(clippy::await_holding_refcell_ref)]
 impl Proxy {
-    pub fn new(params: &JsValue) -> Self {
-        let config: Config =
-            serde_wasm_bindgen::from_value(
-                params.clone(),
-            )
-            .unwrap();
+    // Looks up the element by 'selector'.
+    pub fn new(
+        params: &JsValue,
+    ) -> Result<Self, JsValue> {
+        let config = parse_config(params)?;
+        let element = get_canvas(&config.selector)?;
+        Self::with_element(element, config)
+    }
+
+    // For the given element instead ('selector'
+    // is ignored).
+    pub fn from_canvas(
+        element: HtmlCanvasElement,
+        params: &JsValue,
+    ) -> Result<Self, JsValue> {
+        let config = parse_config(params)?;
+        Self::with_element(element, config)
+    }
 
-        let bgcolor: String = config.bgcolor.clone();
-        let color: String = config.color;
+    fn with_element(
+        element: HtmlCanvasElement,
+        config: Config,
+    ) -> Result<Self, JsValue> {
+        let mut builder = CanvasBuilder::new(
+            config.bgcolor,
+            config.color,
+        )
+        .velocity_coloring(config.velocity_coloring)
+        .double_buffer(config.double_buffer)
+        .streaks(config.streaks)
+        .intro_burst(config.intro_burst)
+        .curved_sticks(config.curved_sticks)
+        .verbose(config.verbose);
+
+        if let Some(v) = config.max_dpr {
+            builder = builder.max_dpr(v);
+        }
+        if let Some(v) = config.use_dpr {
+            builder = builder.use_dpr(v);
+        }
+        if let Some(v) = config.resolution_scale {
+            builder = builder.resolution_scale(v);
+        }
+        if let Some(v) = config.num_of_particles {
+            builder = builder.num_particles(v);
+        }
+        if let Some(v) = config.color2_intensity {
+            builder = builder.color2_intensity(v);
+        }
+        if let Some(v) = config.palette {
+            builder = builder.palette(v);
+        }
+        if let Some(v) = config.seed {
+            builder = builder.seed(v);
+        }
+        if let Some(v) = config.spawn_shape {
+            builder = builder.spawn_shape(v);
+        }
+        if let Some(v) = config.particle_lifetime {
+            builder = builder.particle_lifetime(v);
+        }
+        if let Some(v) = config.separation_radius {
+            builder = builder.separation_radius(v);
+        }
+        if let Some(v) = config.speed {
+            builder = builder.speed(v);
+        }
+        if let Some(v) = config.mobile_breakpoint {
+            builder = builder.mobile_breakpoint(v);
+        }
+        if let Some(v) = config.particle_size_mobile {
+            builder = builder.particle_size_mobile(v);
+        }
+        if let Some(v) = config.particle_size_desktop
+        {
+            builder =
+                builder.particle_size_desktop(v);
+        }
+        if let Some(v) = config.grid_size_mobile {
+            builder = builder.grid_size_mobile(v);
+        }
+        if let Some(v) = config.grid_size_desktop {
+            builder = builder.grid_size_desktop(v);
+        }
+        if let Some(v) = config.edge_behavior {
+            builder = builder.edge_behavior(v);
+        }
+        if let Some(v) = config.wrap_margin {
+            builder = builder.wrap_margin(v);
+        }
+        if let Some(v) = config.flow_mode {
+            builder = builder.flow_mode(v);
+        }
+        if let Some(v) = config.noise_scale {
+            builder = builder.noise_scale(v);
+        }
+        if let Some(v) = config.time_scale {
+            builder = builder.time_scale(v);
+        }
+        if let Some(v) = config.time_divisor {
+            builder = builder.time_divisor(v);
+        }
+        if let Some(v) = config.jitter {
+            builder = builder.jitter(v);
+        }
+        if let Some(v) = config.angle_turns {
+            builder = builder.angle_turns(v);
+        }
+        if let Some(v) = config.octaves {
+            builder = builder.octaves(v);
+        }
+        if let Some(v) = config.lacunarity {
+            builder = builder.lacunarity(v);
+        }
+        if let Some(v) = config.persistence {
+            builder = builder.persistence(v);
+        }
+        if let Some((x, y)) = config.wind {
+            builder = builder.wind(x, y);
+        }
+        if let Some((min, max)) =
+            config.particle_alpha
+        {
+            builder =
+                builder.particle_alpha(min, max);
+        }
+        if let Some(v) = config.size_jitter {
+            builder = builder.size_jitter(v);
+        }
+        if let Some(v) = config.stick_mode {
+            builder = builder.stick_mode(v);
+        }
+        if let Some(v) = config.stick_neighbors {
+            builder = builder.stick_neighbors(v);
+        }
+        if let Some(v) = config.stick_min_length {
+            builder = builder.stick_min_length(v);
+        }
+        if let Some(v) = config.stick_max_length {
+            builder = builder.stick_max_length(v);
+        }
+        if let Some(v) = config.stick_width {
+            builder = builder.stick_width(v);
+        }
+        if let Some(v) = config.stick_cap {
+            builder = builder.stick_cap(v);
+        }
+        if let Some(v) = config.stick_falloff {
+            builder = builder.stick_falloff(v);
+        }
+        if let Some(v) = config.particle_shape {
+            builder = builder.particle_shape(v);
+        }
+        if let Some(v) = config.ripple_range_units {
+            builder = builder.ripple_range_units(v);
+        }
+        if let Some(v) = config.trail_alpha {
+            builder = builder.trail_alpha(v);
+        }
+        if let Some(v) = config.draw_sticks {
+            builder = builder.draw_sticks(v);
+        }
+        if let Some(v) = config.draw_particles {
+            builder = builder.draw_particles(v);
+        }
+        if let Some(v) = config.link_distance {
+            builder = builder.link_distance(v);
+        }
+        if let Some(v) = config.image_smoothing {
+            builder = builder.image_smoothing(v);
+        }
+        if let Some(v) = config.fade_in_frames {
+            builder = builder.fade_in_frames(v);
+        }
+        if let Some(v) = config.easing {
+            builder = builder.easing(v);
+        }
 
-        let element =
-            get_canvas("#perlin-experiment").unwrap();
         let canvas = Rc::new(RefCell::new(
-            Canvas::new(element, bgcolor, color),
+            builder.build(element)?,
         ));
 
-        canvas.borrow_mut().register_listeners();
-        canvas.borrow_mut().update_size();
+        Canvas::register_listeners(&canvas);
+
+        Ok(Proxy {
+            canvas,
+            paused: Rc::new(Cell::new(false)),
+            destroyed: Rc::new(Cell::new(false)),
+            running: Rc::new(Cell::new(false)),
+            frame_timer: Rc::new(RefCell::new(
+                FrameTimer::new(),
+            )),
+            target_fps: config
+                .target_fps
+                .filter(|&fps| fps > 0),
+            refresh_rate_ms: Rc::new(Cell::new(
+                config.refresh_rate_ms.unwrap_or(0),
+            )),
+            last_update: Rc::new(Cell::new(None)),
+            on_frame: Rc::new(RefCell::new(None)),
+        })
+    }
+
+    pub fn pause(&self) {
+        self.paused.set(true);
+    }
 
-        Proxy { canvas }
+    pub fn resume(&self) {
+        self.paused.set(false);
     }
 
+    // Stops the loop in 'run', and removes
+    // the resize listener.
+    pub fn destroy(&self) {
+        self.destroyed.set(true);
+        self.canvas
+            .borrow_mut()
+            .unregister_listeners();
+    }
+
+    pub fn fps(&self) -> f64 {
+        self.frame_timer.borrow().fps()
+    }
+
+    // Same as 'Config::from_canvas', but with
+    // options of 'Proxy' as well.
+    pub fn config(&self) -> Config {
+        let mut config = Config::from_canvas(
+            &self.canvas.borrow(),
+        );
+        config.target_fps = self.target_fps;
+        config.refresh_rate_ms =
+            Some(self.refresh_rate_ms.get());
+        config
+    }
+
+    // Cloned so that the callback may replace
+    // (or clear) itself. Errors thrown in it
+    // are logged, and the loop goes on.
+    fn call_on_frame(&self) {
+        let callback = self.on_frame.borrow().clone();
+        if let Some(callback) = callback {
+            let frame =
+                self.canvas.borrow().sim.frame;
+            if let Err(err) = callback.call2(
+                &JsValue::NULL,
+                &frame.into(),
+                &self.fps().into(),
+            ) {
+                console::error_2(
+                    &"[proxy] 'on_frame' failed:"
+                        .into(),
+                    &err,
+                );
+            }
+        }
+    }
+
+    pub fn tick(&self) {
+        let now = Instant::now();
+        let dt = self.last_update.get().map_or(
+            1.0 / 60.0,
+            |last| {
+                now.duration_since(last)
+                    .as_secs_f64()
+                    .min(MAX_DT)
+            },
+        );
+        self.last_update.set(Some(now));
+
+        self.canvas.borrow_mut().update(dt);
+        self.canvas.borrow_mut().draw();
+    }
+
+    // NOTE: We used to wait for 60 msec before
+    // every frame (in addition to waiting for
+    // 'requestAnimationFrame') which capped
+    // the animation to about 16 FPS.
+    // Now, it is driven purely by
+    // 'requestAnimationFrame', unless
+    // 'target_fps' or 'refresh_rate_ms' is
+    // given, in which case we wait for
+    // the longer of the two, and then for
+    // 'requestAnimationFrame' (so frames are
+    // never faster than the display).
     pub async fn run(&mut self) {
-        loop {
-            timer(REFRESH_RATE).await.unwrap();
-            self.canvas.borrow_mut().update();
-            self.canvas.borrow_mut().draw();
+        self.running.set(true);
+        let mut last_tick: Option<Instant> = None;
+        while !self.destroyed.get() {
+            let interval =
+                self.target_fps
+                    .map_or(0.0, |fps| {
+                        1000.0 / fps as f64
+                    })
+                    .max(self.refresh_rate_ms.get()
+                        as f64);
+            // Never waits when 'interval' is 0.
+            if let Some(last_tick) = last_tick {
+                let elapsed =
+                    last_tick.elapsed().as_secs_f64()
+                        * 1000.0;
+                if elapsed < interval {
+                    timer(
+                        (interval - elapsed) as i32,
+                    )
+                    .await
+                    .unwrap();
+                }
+            }
             request_animation_frame_future().await;
+            last_tick = Some(Instant::now());
+
+            // When paused, we keep looping, but
+            // without updating nor drawing.
+            // Since 'frame' does not advance,
+            // resuming continues from the same
+            // noise phase.
+            if !self.paused.get() {
+                self.tick();
+                self.frame_timer
+                    .borrow_mut()
+                    .record(Instant::now());
+                self.call_on_frame();
+            } else {
+                self.frame_timer.borrow_mut().skip();
+                self.last_update.set(None);
+            }
         }
+        self.running.set(false);
     }
 }
@@ -0,0 +1,1927 @@
+/// Particles and the flow moving them,
+/// without anything to do with the DOM
+/// (drawing them is done by 'Canvas').
+/// So, this can be tested with 'cargo test'.
+use noise::{NoiseFn, Perlin};
+use rand::distributions::Uniform;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+use crate::grid::SpatialGrid;
+use crate::utils::{lerp, toroidal_delta, Easing};
+
+pub const SPEED: f64 = 3.0;
+
+// Movement is defined as per frame at this FPS,
+// and is scaled for other frame rates.
+pub const BASE_FPS: f64 = 60.0;
+pub const MAX_SPEED: f64 = 50.0;
+
+// Frames (at 'BASE_FPS') for the noise time
+// to advance by 1.0 (at 'time_scale' 1.0).
+pub const TIME_DIVISOR: f64 = 100.0;
+
+// For 'FlowMode::Spiral'. A quarter turn
+// would be a perfect circle, and we want
+// it slightly less so that it spirals out.
+const SPIRAL_ROTATION: f64 = PI * 0.4;
+
+pub const JITTER: f64 = 0.1;
+
+// For 'SpawnShape::Ring'. The inner radius
+// relative to the outer one.
+const RING_INNER_RATIO: f64 = 0.7;
+
+// Rejection sampling gives up after this
+// many tries (for a particle), and spawns it
+// anywhere (ex. a mostly dark mask).
+const MAX_MASK_TRIES: usize = 1000;
+
+// For 'separation_radius'. How much particles
+// right on top of each other are pushed apart
+// (relative to how much the flow moves them).
+const SEPARATION_STRENGTH: f64 = 0.3;
+
+// For 'intro_burst'.
+pub const INTRO_BURST_FRAMES: u32 = 60;
+
+// For 'zoom'. 0 would sample a single point.
+const MIN_ZOOM: f64 = 0.01;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Particle {
+    pub x: f64,
+    pub y: f64,
+    pub angle: f64,
+    // Displacement in the last frame.
+    #[serde(default)]
+    pub vx: f64,
+    #[serde(default)]
+    pub vy: f64,
+    // For 'EdgeBehavior::Bounce'.
+    // Tells whether the particle has been
+    // reflected (odd number of times)
+    // on either axis.
+    #[serde(skip)]
+    pub flip_x: bool,
+    #[serde(skip)]
+    pub flip_y: bool,
+    // Frames since it was spawned.
+    // Only counted with 'particle_lifetime'.
+    #[serde(default)]
+    pub age: u32,
+    // Into the palette of the canvas.
+    #[serde(default)]
+    pub color_index: usize,
+    // Opacity (0.0 to 1.0). Fainter ones look
+    // farther away.
+    #[serde(default = "default_one")]
+    pub alpha: f64,
+    // Multiplied to 'particle_size' when
+    // drawing. It doesn't change the speed
+    // (which is still by 'particle_size').
+    #[serde(default = "default_one")]
+    pub size_factor: f64,
+    // Position before the last update
+    // (for streaks).
+    #[serde(skip)]
+    pub prev_x: f64,
+    #[serde(skip)]
+    pub prev_y: f64,
+}
+
+fn default_one() -> f64 {
+    1.0
+}
+
+impl Particle {
+    pub fn new(x: f64, y: f64, angle: f64) -> Self {
+        Particle {
+            x,
+            y,
+            angle,
+            vx: 0.0,
+            vy: 0.0,
+            flip_x: false,
+            flip_y: false,
+            age: 0,
+            color_index: 0,
+            alpha: 1.0,
+            size_factor: 1.0,
+            prev_x: x,
+            prev_y: y,
+        }
+    }
+
+    pub fn velocity(&self) -> f64 {
+        self.vx.hypot(self.vy)
+    }
+}
+
+// What particles do when reaching the edges.
+// For 'Bounce', the angle is still taken from
+// the noise each frame, and reflecting it
+// would last only for a frame. So, instead,
+// we keep 'flip_x' and 'flip_y' for each particle
+// which negate the velocity on the axis
+// until it reaches the opposite edge.
+// 'SoftBounds' never moves particles at
+// the edges. Instead, those within 'margin'
+// (in pixels) from the edges are pushed back
+// in, harder as they go deeper (or beyond).
+// With 'strength' 1.0, a particle right at
+// the edge is pushed as much as the flow
+// moves it.
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeBehavior {
+    Wrap,
+    Bounce,
+    SoftBounds { margin: f64, strength: f64 },
+}
+
+// How the noise is mapped to angles.
+// 'Perlin' uses the noise as is.
+// 'Radial' points away from the center,
+// modulated by the noise. 'Spiral' is 'Radial'
+// with a constant rotation which makes
+// particles swirl around the center.
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowMode {
+    Perlin,
+    Radial,
+    Spiral,
+}
+
+// Where particles are generated (on resize).
+// 'Circle' and 'Ring' are centered, and fit
+// in the shorter side of the canvas.
+// Later spawns (ex. respawning) are always
+// in the whole rectangle.
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SpawnShape {
+    Rect,
+    Circle,
+    Ring,
+}
+
+// Grayscale (one byte per pixel, row by row)
+// which is stretched over the canvas.
+// Particles spawn more where it is brighter.
+#[derive(Debug, Clone)]
+pub struct SpawnMask {
+    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+}
+
+// Particles moving out from the center to
+// where they were generated ('targets').
+#[derive(Debug, Clone)]
+pub struct IntroBurst {
+    pub targets: Vec<(f64, f64)>,
+    pub frames: u32,
+    pub progress: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub width: f64,
+    pub height: f64,
+    pub noise: Perlin,
+    pub seed: Option<u32>,
+    pub rng: StdRng,
+    pub frame: i32,
+    // Accumulated time in frames at 'BASE_FPS'
+    // (which is what 'frame' would be at 60 FPS).
+    pub time: f64,
+    // Where we are on the time axis of the noise.
+    // It advances by 'time_scale' every
+    // 'time_divisor' frames (at 'BASE_FPS'),
+    // and can be set directly
+    // to scrub through the flow.
+    pub noise_time: f64,
+    pub speed: f64,
+    pub edge_behavior: EdgeBehavior,
+    // How far (in pixels) particles go beyond
+    // the edges before they wrap around.
+    // Larger the margin, later they wrap, so that
+    // particles (and their trails) fully exit
+    // before reappearing on the other side.
+    // 'None' for 'particle_size'.
+    pub wrap_margin: Option<f64>,
+    pub flow_mode: FlowMode,
+    // Spatial frequency of the noise.
+    // Lower the value, broader the swirls.
+    pub noise_scale: f64,
+    // Moves (in pixels) and magnifies the field
+    // under particles (which stay where they
+    // are), as if it was drawn with
+    // 'translate(pan)' and 'scale(zoom)'.
+    // Panning reveals other parts of the noise.
+    pub pan: (f64, f64),
+    pub zoom: f64,
+    // How fast the noise changes over time.
+    pub time_scale: f64,
+    // Same as 'time_scale', but inverted.
+    // Smaller the value, faster the flow
+    // itself changes (not particles).
+    pub time_divisor: f64,
+    // Number of rotations the noise range maps to.
+    // Larger the value, tighter the curls.
+    pub angle_turns: f64,
+    // Random offset added to the noise
+    // coordinates. 0.0 for deterministic.
+    pub jitter: f64,
+    // For fractal Brownian motion, we sum
+    // 'octaves' layers of the noise, each
+    // 'lacunarity' times finer and 'persistence'
+    // times weaker than the previous one.
+    // 1 octave is the plain noise.
+    pub octaves: u32,
+    pub lacunarity: f64,
+    pub persistence: f64,
+    // Constant drift on top of the flow.
+    // (1.0, 0.0) moves particles to the right
+    // as fast as the flow does.
+    pub wind: (f64, f64),
+    // (x, y, strength) in canvas pixels.
+    // Positive strength pulls particles in,
+    // and negative pushes them away.
+    pub attractor: Option<(f64, f64, f64)>,
+    // In pixels. Particles closer than this
+    // push each other away (gently), so that
+    // they won't pile up where the flow is weak.
+    pub separation_radius: Option<f64>,
+    pub particles: Vec<Particle>,
+    pub num_of_particles: usize,
+    pub spawn_shape: SpawnShape,
+    // When given, used instead of 'spawn_shape'.
+    pub spawn_mask: Option<SpawnMask>,
+    // Newly generated particles (on resize)
+    // burst out from the center.
+    pub intro_burst: bool,
+    // While bursting.
+    pub intro: Option<IntroBurst>,
+    // Particles get random 'color_index'
+    // below this when spawned.
+    pub num_of_colors: usize,
+    // (min, max) of random 'alpha' for
+    // particles when spawned.
+    pub alpha_range: (f64, f64),
+    // Particles get random 'size_factor'
+    // within 1.0 +/- this.
+    pub size_jitter: f64,
+    // In frames. When given, particles are
+    // respawned somewhere else as they get old
+    // so that the pattern never settles.
+    pub particle_lifetime: Option<u32>,
+    // Particles stay where they are while
+    // the flow keeps changing, and sticks
+    // still follow it (unlike pausing).
+    pub freeze_particles: bool,
+    // The opposite. The flow stops changing,
+    // and particles keep their last angles.
+    pub freeze_field: bool,
+    pub particle_size: f64,
+    // Particles move relative to it
+    // (see 'attractor').
+    pub unit_size: f64,
+}
+
+impl Simulation {
+    pub fn new(
+        seed: Option<u32>,
+        num_of_particles: usize,
+    ) -> Self {
+        // When 'seed' is given, the noise and
+        // every random value we use are seeded,
+        // and the animation is reproducible.
+        // Otherwise, it is random as before.
+        let (noise, rng) = match seed {
+            Some(seed) => (
+                Perlin::new(seed),
+                StdRng::seed_from_u64(seed as u64),
+            ),
+            None => (
+                Perlin::default(),
+                StdRng::from_entropy(),
+            ),
+        };
+
+        Simulation {
+            width: 0.0,
+            height: 0.0,
+            noise,
+            seed,
+            rng,
+            frame: 0,
+            time: 0.0,
+            noise_time: 0.0,
+            speed: SPEED,
+            edge_behavior: EdgeBehavior::Wrap,
+            wrap_margin: None,
+            flow_mode: FlowMode::Perlin,
+            noise_scale: 1.0,
+            pan: (0.0, 0.0),
+            zoom: 1.0,
+            time_scale: 1.0,
+            time_divisor: TIME_DIVISOR,
+            angle_turns: 1.0,
+            jitter: JITTER,
+            octaves: 1,
+            lacunarity: 2.0,
+            persistence: 0.5,
+            wind: (0.0, 0.0),
+            attractor: None,
+            separation_radius: None,
+            particles: Vec::new(),
+            num_of_particles,
+            spawn_shape: SpawnShape::Rect,
+            spawn_mask: None,
+            intro_burst: false,
+            intro: None,
+            num_of_colors: 1,
+            alpha_range: (1.0, 1.0),
+            size_jitter: 0.0,
+            particle_lifetime: None,
+            freeze_particles: false,
+            freeze_field: false,
+            particle_size: 1.0,
+            unit_size: 1.0,
+        }
+    }
+
+    pub fn set_view(
+        &mut self,
+        pan: (f64, f64),
+        zoom: f64,
+    ) {
+        self.pan = pan;
+        self.zoom = zoom.max(MIN_ZOOM);
+    }
+
+    // Setting it to 0 freezes particles.
+    // NaN or infinity is ignored (it would
+    // send every particle to NaN).
+    pub fn set_speed(&mut self, speed: f64) {
+        if speed.is_finite() {
+            self.speed = speed.clamp(0.0, MAX_SPEED);
+        }
+    }
+
+    // Sizes in pixels. Starts over with
+    // newly generated particles.
+    // Negative or non-finite sizes are taken
+    // as 0 (spawning would panic on them).
+    pub fn resize(
+        &mut self,
+        width: f64,
+        height: f64,
+    ) {
+        let valid = |v: f64| {
+            if v.is_finite() {
+                v.max(0.0)
+            } else {
+                0.0
+            }
+        };
+        let (width, height) =
+            (valid(width), valid(height));
+        self.width = width;
+        self.height = height;
+        self.frame = 0;
+        self.time = 0.0;
+        self.noise_time = 0.0;
+        self.particles = match &self.spawn_mask {
+            Some(mask) => generate_particles_masked(
+                &mut self.rng,
+                width,
+                height,
+                self.num_of_particles,
+                &mask.data,
+                mask.width,
+                mask.height,
+            ),
+            None => generate_particles(
+                &mut self.rng,
+                width,
+                height,
+                self.num_of_particles,
+                self.spawn_shape,
+            ),
+        };
+        for p in &mut self.particles {
+            randomize_looks(
+                p,
+                &mut self.rng,
+                self.num_of_colors,
+                self.alpha_range,
+                self.size_jitter,
+            );
+        }
+
+        // Replaces the one in progress (if any),
+        // so it starts over cleanly.
+        self.intro = None;
+        if self.intro_burst {
+            let (cx, cy) =
+                (width / 2.0, height / 2.0);
+            self.intro = Some(IntroBurst {
+                targets: self
+                    .particles
+                    .iter()
+                    .map(|p| (p.x, p.y))
+                    .collect(),
+                frames: INTRO_BURST_FRAMES,
+                progress: 0,
+            });
+            for p in &mut self.particles {
+                p.x = cx;
+                p.y = cy;
+            }
+        }
+
+        // Random ages so that they don't
+        // all respawn at once.
+        if let Some(lifetime) = self.particle_lifetime
+        {
+            for p in &mut self.particles {
+                p.age = self
+                    .rng
+                    .gen_range(0, lifetime.max(1));
+            }
+        }
+    }
+
+    // Changes the flow field. Unless we
+    // 'regenerate' particles, they continue
+    // from where they are (and so does 'frame').
+    pub fn set_seed(
+        &mut self,
+        seed: u32,
+        regenerate: bool,
+    ) {
+        self.seed = Some(seed);
+        self.noise = Perlin::new(seed);
+        self.rng = StdRng::seed_from_u64(seed as u64);
+        if regenerate {
+            self.resize(self.width, self.height);
+        }
+    }
+
+    // At random positions. Unlike 'resize',
+    // the rest of particles stay where they are.
+    pub fn add_particles(&mut self, n: usize) {
+        for _ in 0..n {
+            let mut p = spawn_particle(
+                &mut self.rng,
+                self.width,
+                self.height,
+            );
+            randomize_looks(
+                &mut p,
+                &mut self.rng,
+                self.num_of_colors,
+                self.alpha_range,
+                self.size_jitter,
+            );
+            if let Some(lifetime) =
+                self.particle_lifetime
+            {
+                p.age = self
+                    .rng
+                    .gen_range(0, lifetime.max(1));
+            }
+            self.particles.push(p);
+        }
+        self.num_of_particles = self.particles.len();
+    }
+
+    // The newest ones go first.
+    pub fn remove_particles(&mut self, n: usize) {
+        let len = self.particles.len();
+        self.particles
+            .truncate(len.saturating_sub(n));
+        self.num_of_particles = self.particles.len();
+    }
+
+    // New particles in the same size.
+    // With 'seed', we start over from the same
+    // random sequence, and get the same layout
+    // as the first one (for the same size).
+    pub fn reset(&mut self) {
+        if let Some(seed) = self.seed {
+            self.rng =
+                StdRng::seed_from_u64(seed as u64);
+        }
+        self.resize(self.width, self.height);
+    }
+
+    // Particles stay where they are.
+    // Only the flow changes.
+    pub fn set_noise_time(&mut self, t: f64) {
+        self.noise_time = t;
+    }
+
+    // Negative to go backward.
+    pub fn step_noise_time(&mut self, delta: f64) {
+        self.noise_time += delta;
+    }
+
+    // The noise with 'octaves'.
+    pub fn fbm(&self, x: f64, y: f64, z: f64) -> f64 {
+        fbm(
+            &self.noise,
+            [x, y, z],
+            self.octaves,
+            self.lacunarity,
+            self.persistence,
+        )
+    }
+
+    // Direction of the flow at (x, y) in pixels,
+    // which is what particles there follow
+    // (except for 'jitter').
+    pub fn field_angle(&self, x: f64, y: f64) -> f64 {
+        let (w, h) = (self.width, self.height);
+        if !has_area(w, h) {
+            return 0.0;
+        }
+        let (nx, ny) = noise_xy(
+            x,
+            y,
+            (w, h),
+            self.noise_scale,
+            self.pan,
+            self.zoom,
+        );
+        let noise_val =
+            self.fbm(nx, ny, self.noise_time);
+        flow_angle(
+            self.flow_mode,
+            noise_val,
+            self.angle_turns,
+            (x, y),
+            (w, h),
+        )
+    }
+
+    // Called from 'Canvas.update'.
+    // 'dt' is seconds since the last frame.
+    pub fn update(&mut self, dt: f64) {
+        // Before the element is laid out, sizes
+        // may be 0, and 'p.x / w' would give us
+        // NaN which never goes away.
+        if !has_area(self.width, self.height) {
+            return;
+        }
+
+        // 1.0 at 60 FPS.
+        let steps = dt * BASE_FPS;
+
+        self.frame += 1;
+        self.time += steps;
+        if !self.freeze_field {
+            self.noise_time += steps
+                / self.time_divisor
+                * self.time_scale;
+        }
+
+        if self.intro.is_some() {
+            self.advance_intro();
+            return;
+        }
+
+        // From where they are before moving.
+        let pushes = match self.separation_radius {
+            Some(radius)
+                if !self.freeze_particles =>
+            {
+                separation_pushes(
+                    &self.particles,
+                    self.width,
+                    self.height,
+                    radius,
+                    matches!(
+                        self.edge_behavior,
+                        EdgeBehavior::Wrap
+                    ),
+                )
+            }
+            _ => Vec::new(),
+        };
+
+        for (i, p) in
+            self.particles.iter_mut().enumerate()
+        {
+            let w = self.width;
+            let h = self.height;
+
+            p.prev_x = p.x;
+            p.prev_y = p.y;
+
+            // Just in case (ex. extreme 'dt'),
+            // since the noise would panic on them.
+            // Starts over from somewhere random.
+            if !p.x.is_finite() || !p.y.is_finite() {
+                p.x = self.rng.gen_range(0.0, w);
+                p.y = self.rng.gen_range(0.0, h);
+                p.vx = 0.0;
+                p.vy = 0.0;
+            }
+
+            // Respawning would move them.
+            if let (Some(lifetime), false) = (
+                self.particle_lifetime,
+                self.freeze_particles,
+            ) {
+                p.age += 1;
+                if p.age > lifetime {
+                    // Keeps the looks so that
+                    // the mix stays the same.
+                    *p = Particle {
+                        color_index: p.color_index,
+                        alpha: p.alpha,
+                        size_factor: p.size_factor,
+                        ..spawn_particle(
+                            &mut self.rng,
+                            w,
+                            h,
+                        )
+                    };
+                }
+            }
+
+            // With 'freeze_field', particles keep
+            // going in their last direction.
+            let angle = if self.freeze_field {
+                p.angle
+            } else {
+                // We used to think that, without
+                // random values, all particles would
+                // have the same positions and angles.
+                // However, each particle already has
+                // its own 'x' and 'y', and samples
+                // the noise at a different point.
+                // So, 'jitter' is not necessary, and
+                // only adds a little wobble to
+                // the movement. Set it to 0.0 for
+                // fully deterministic sampling.
+                let (jitter_x, jitter_y) =
+                    if self.jitter > 0.0 {
+                        (
+                            self.rng.gen_range(
+                                -self.jitter,
+                                self.jitter,
+                            ),
+                            self.rng.gen_range(
+                                -self.jitter,
+                                self.jitter,
+                            ),
+                        )
+                    } else {
+                        (0.0, 0.0)
+                    };
+
+                let (nx, ny) = noise_xy(
+                    p.x,
+                    p.y,
+                    (w, h),
+                    self.noise_scale,
+                    self.pan,
+                    self.zoom,
+                );
+
+                // Not 'self.fbm' since we are
+                // borrowing 'self.particles'.
+                let noise_val = fbm(
+                    &self.noise,
+                    [
+                        nx + jitter_x,
+                        ny + jitter_y,
+                        self.noise_time,
+                    ],
+                    self.octaves,
+                    self.lacunarity,
+                    self.persistence,
+                );
+
+                flow_angle(
+                    self.flow_mode,
+                    noise_val,
+                    self.angle_turns,
+                    (p.x, p.y),
+                    (w, h),
+                )
+            };
+
+            // Sticks and snapshots read it.
+            // It is the flow's direction, and
+            // doesn't include flips nor wind.
+            p.angle = angle;
+
+            if self.freeze_particles {
+                p.vx = 0.0;
+                p.vy = 0.0;
+                continue;
+            }
+
+            let (mut dx, mut dy) = (
+                self.speed * angle.cos(),
+                self.speed * angle.sin(),
+            );
+
+            if p.flip_x {
+                dx = -dx;
+            }
+            if p.flip_y {
+                dy = -dy;
+            }
+
+            let size = self.particle_size;
+            let margin =
+                self.wrap_margin.unwrap_or(size);
+
+            p.vx = (dx + self.wind.0 * self.speed)
+                * size
+                * steps;
+            p.vy = (dy + self.wind.1 * self.speed)
+                * size
+                * steps;
+
+            // Weaker as particles get farther.
+            // Within one unit, it moves particles
+            // by 'strength' as much as 'speed'
+            // does (at most, either way).
+            if let Some((ax, ay, strength)) =
+                self.attractor
+            {
+                let (to_x, to_y) =
+                    (ax - p.x, ay - p.y);
+                let dist = to_x.hypot(to_y);
+                if dist > f64::EPSILON {
+                    // Not to overshoot the point.
+                    let pull = (strength
+                        * size
+                        * steps
+                        * self.unit_size
+                        / dist.max(self.unit_size))
+                    .min(dist);
+                    p.vx += to_x / dist * pull;
+                    p.vy += to_y / dist * pull;
+                }
+            }
+
+            if let EdgeBehavior::SoftBounds {
+                margin,
+                strength,
+            } = self.edge_behavior
+            {
+                let push = strength
+                    * self.speed
+                    * size
+                    * steps;
+                p.vx +=
+                    soft_bounds_push(p.x, w, margin)
+                        * push;
+                p.vy +=
+                    soft_bounds_push(p.y, h, margin)
+                        * push;
+            }
+
+            if let Some(&(push_x, push_y)) =
+                pushes.get(i)
+            {
+                let push = SEPARATION_STRENGTH
+                    * self.speed
+                    * size
+                    * steps;
+                p.vx += push_x * push;
+                p.vy += push_y * push;
+            }
+
+            p.x += p.vx;
+            p.y += p.vy;
+
+            keep_inside(
+                p,
+                self.edge_behavior,
+                w,
+                h,
+                margin,
+            );
+        }
+    }
+
+    // Instead of the flow while bursting.
+    // Angles still follow the flow so that
+    // sticks look the same as usual.
+    fn advance_intro(&mut self) {
+        let (cx, cy) =
+            (self.width / 2.0, self.height / 2.0);
+        if let Some(intro) = &mut self.intro {
+            intro.progress += 1;
+            let t = Easing::OutQuad.apply(
+                intro.progress as f64
+                    / intro.frames.max(1) as f64,
+            );
+            // Those added during the intro
+            // have no targets, and stay.
+            for (p, &(tx, ty)) in self
+                .particles
+                .iter_mut()
+                .zip(&intro.targets)
+            {
+                p.prev_x = p.x;
+                p.prev_y = p.y;
+                p.x = lerp(t, cx, tx);
+                p.y = lerp(t, cy, ty);
+            }
+            if intro.progress >= intro.frames {
+                self.intro = None;
+            }
+        }
+        for i in 0..self.particles.len() {
+            let (x, y) = (
+                self.particles[i].x,
+                self.particles[i].y,
+            );
+            self.particles[i].angle =
+                self.field_angle(x, y);
+        }
+    }
+
+    // Pushes particles away from (x, y) once.
+    // Within one unit from the point, they are
+    // pushed by 'strength' units, and less as
+    // they get farther. Negative 'strength'
+    // pulls them in.
+    pub fn impulse(
+        &mut self,
+        x: f64,
+        y: f64,
+        strength: f64,
+    ) {
+        let (w, h) = (self.width, self.height);
+        let unit = self.unit_size;
+        let margin = self
+            .wrap_margin
+            .unwrap_or(self.particle_size);
+        for p in &mut self.particles {
+            let (from_x, from_y) = (p.x - x, p.y - y);
+            let dist = from_x.hypot(from_y);
+            if dist <= f64::EPSILON {
+                continue;
+            }
+            let push = strength * unit * unit
+                / dist.max(unit);
+            p.x += from_x / dist * push;
+            p.y += from_y / dist * push;
+            keep_inside(
+                p,
+                self.edge_behavior,
+                w,
+                h,
+                margin,
+            );
+        }
+    }
+}
+
+// For each particle, the direction to get
+// away from others within 'radius'. Closer
+// ones push harder, and it is at most 1.0
+// however crowded it is. With 'wraps',
+// neighbors across the edges push too.
+fn separation_pushes(
+    particles: &[Particle],
+    width: f64,
+    height: f64,
+    radius: f64,
+    wraps: bool,
+) -> Vec<(f64, f64)> {
+    let wrap = if wraps {
+        Some((width, height))
+    } else {
+        None
+    };
+    let mut pushes =
+        vec![(0.0, 0.0); particles.len()];
+    if radius <= 0.0 {
+        return pushes;
+    }
+    let grid = SpatialGrid::build(
+        particles.iter().map(|p| (p.x, p.y)),
+        width,
+        height,
+        radius,
+    );
+    for (i, a) in particles.iter().enumerate() {
+        let (mut push_x, mut push_y) = (0.0, 0.0);
+        for j in grid.nearby(a.x, a.y, radius, wrap) {
+            let b = &particles[j];
+            let (from_x, from_y) = match wrap {
+                Some((w, h)) => toroidal_delta(
+                    b.x, b.y, a.x, a.y, w, h,
+                ),
+                None => (a.x - b.x, a.y - b.y),
+            };
+            let dist = from_x.hypot(from_y);
+            // Itself, or exactly on top of it
+            // (no way to tell which way to go).
+            if i == j
+                || dist <= f64::EPSILON
+                || dist >= radius
+            {
+                continue;
+            }
+            let weight = 1.0 - dist / radius;
+            push_x += from_x / dist * weight;
+            push_y += from_y / dist * weight;
+        }
+        let len = push_x.hypot(push_y);
+        pushes[i] = if len > 1.0 {
+            (push_x / len, push_y / len)
+        } else {
+            (push_x, push_y)
+        };
+    }
+    pushes
+}
+
+// Wraps or bounces particles
+// which went off the edges.
+fn keep_inside(
+    p: &mut Particle,
+    edge_behavior: EdgeBehavior,
+    w: f64,
+    h: f64,
+    margin: f64,
+) {
+    match edge_behavior {
+        EdgeBehavior::Wrap => {
+            if p.x < -margin {
+                p.x = w + margin;
+            }
+            if p.y < -margin {
+                p.y = h + margin;
+            }
+            if p.x > w + margin {
+                p.x = -margin;
+            }
+            if p.y > h + margin {
+                p.y = -margin;
+            }
+        }
+        EdgeBehavior::Bounce => {
+            if p.x < 0.0 || p.x > w {
+                p.x = p.x.clamp(0.0, w);
+                p.flip_x = !p.flip_x;
+            }
+            if p.y < 0.0 || p.y > h {
+                p.y = p.y.clamp(0.0, h);
+                p.flip_y = !p.flip_y;
+            }
+        }
+        // Pushed back in 'update'.
+        EdgeBehavior::SoftBounds { .. } => {}
+    }
+}
+
+// For 'EdgeBehavior::SoftBounds'. How far
+// 'pos' is in the margin on either side of
+// 'len' (1.0 right at the edge), positive
+// when it should go up, and 0 elsewhere.
+fn soft_bounds_push(
+    pos: f64,
+    len: f64,
+    margin: f64,
+) -> f64 {
+    let margin = margin.max(1.0);
+    if pos < margin {
+        (margin - pos) / margin
+    } else if pos > len - margin {
+        -(pos - (len - margin)) / margin
+    } else {
+        0.0
+    }
+}
+
+// From pixels to where we sample the noise.
+// Canvas sizes map to 'noise_scale' (at
+// 'zoom' 1.0).
+fn noise_xy(
+    x: f64,
+    y: f64,
+    (w, h): (f64, f64),
+    noise_scale: f64,
+    pan: (f64, f64),
+    zoom: f64,
+) -> (f64, f64) {
+    (
+        (x - pan.0) / zoom / w * noise_scale,
+        (y - pan.1) / zoom / h * noise_scale,
+    )
+}
+
+// Divided by the sum of amplitudes so that
+// it stays in the same range as the noise.
+pub fn fbm(
+    noise: &Perlin,
+    point: [f64; 3],
+    octaves: u32,
+    lacunarity: f64,
+    persistence: f64,
+) -> f64 {
+    let mut sum = 0.0;
+    let mut total_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude
+            * noise.get([
+                point[0] * frequency,
+                point[1] * frequency,
+                point[2] * frequency,
+            ]);
+        total_amplitude += amplitude;
+        amplitude *= persistence;
+        frequency *= lacunarity;
+    }
+    sum / total_amplitude
+}
+
+// Turns the noise into an angle. 'Radial' and
+// 'Spiral' go around the center.
+fn flow_angle(
+    mode: FlowMode,
+    noise_val: f64,
+    turns: f64,
+    (x, y): (f64, f64),
+    (w, h): (f64, f64),
+) -> f64 {
+    let (cx, cy) = (w / 2.0, h / 2.0);
+    match mode {
+        FlowMode::Perlin => {
+            noise_val * PI * 2.0 * turns
+        }
+        FlowMode::Radial => {
+            (y - cy).atan2(x - cx)
+                + noise_val * PI * turns
+        }
+        FlowMode::Spiral => {
+            (y - cy).atan2(x - cx)
+                + noise_val * PI * turns
+                + SPIRAL_ROTATION
+        }
+    }
+}
+
+fn has_area(width: f64, height: f64) -> bool {
+    width.is_finite()
+        && height.is_finite()
+        && width > f64::EPSILON
+        && height > f64::EPSILON
+}
+
+pub fn generate_particles(
+    rng: &mut StdRng,
+    width: f64,
+    height: f64,
+    count: usize,
+    shape: SpawnShape,
+) -> Vec<Particle> {
+    let (cx, cy) = (width / 2.0, height / 2.0);
+    let radius = cx.min(cy);
+    (0..count)
+        .map(|_| match shape {
+            SpawnShape::Rect => {
+                spawn_particle(rng, width, height)
+            }
+            SpawnShape::Circle => {
+                spawn_particle_in_ring(
+                    rng,
+                    (cx, cy),
+                    0.0,
+                    radius,
+                )
+            }
+            SpawnShape::Ring => {
+                spawn_particle_in_ring(
+                    rng,
+                    (cx, cy),
+                    radius * RING_INNER_RATIO,
+                    radius,
+                )
+            }
+        })
+        .collect()
+}
+
+// Denser where 'mask' is brighter.
+// Positions are picked at random, and kept
+// by the chance of the brightness (relative
+// to the brightest pixel). Without any bright
+// pixels, it is the same as 'SpawnShape::Rect'.
+pub fn generate_particles_masked(
+    rng: &mut StdRng,
+    width: f64,
+    height: f64,
+    count: usize,
+    mask: &[u8],
+    mask_w: usize,
+    mask_h: usize,
+) -> Vec<Particle> {
+    let pixels = mask_w * mask_h;
+    let max = mask
+        .iter()
+        .take(pixels)
+        .copied()
+        .max()
+        .unwrap_or(0);
+    if max == 0 || mask.len() < pixels {
+        return generate_particles(
+            rng,
+            width,
+            height,
+            count,
+            SpawnShape::Rect,
+        );
+    }
+    let brightness = |x: f64, y: f64| {
+        let col = ((x / width * mask_w as f64)
+            as usize)
+            .min(mask_w - 1);
+        let row = ((y / height * mask_h as f64)
+            as usize)
+            .min(mask_h - 1);
+        mask[row * mask_w + col] as f64
+    };
+    (0..count)
+        .map(|_| {
+            for _ in 0..MAX_MASK_TRIES {
+                let p = spawn_particle(
+                    rng, width, height,
+                );
+                if rng.gen::<f64>() * (max as f64)
+                    < brightness(p.x, p.y)
+                {
+                    return p;
+                }
+            }
+            spawn_particle(rng, width, height)
+        })
+        .collect()
+}
+
+// Evenly distributed over the area
+// between 'inner' and 'outer' radius.
+fn spawn_particle_in_ring(
+    rng: &mut StdRng,
+    center: (f64, f64),
+    inner: f64,
+    outer: f64,
+) -> Particle {
+    let theta = rng.gen_range(0.0, 2.0 * PI);
+    // Without 'sqrt', particles would gather
+    // around the center.
+    let r = rng
+        .gen::<f64>()
+        .mul_add(
+            outer * outer - inner * inner,
+            inner * inner,
+        )
+        .sqrt();
+    Particle::new(
+        center.0 + r * theta.cos(),
+        center.1 + r * theta.sin(),
+        rng.gen_range(0.0, 2.0 * PI),
+    )
+}
+
+// A particle at a random position.
+pub fn spawn_particle(
+    rng: &mut StdRng,
+    width: f64,
+    height: f64,
+) -> Particle {
+    // Inclusive, so that 0 sizes won't panic.
+    let x_range = Uniform::new_inclusive(0.0, width);
+    let y_range = Uniform::new_inclusive(0.0, height);
+    let angle_range = Uniform::new(0.0, 2.0 * PI);
+
+    Particle::new(
+        rng.sample(x_range),
+        rng.sample(y_range),
+        rng.sample(angle_range),
+    )
+}
+
+// Color, opacity, and size. Not in
+// 'spawn_particle' since respawned ones
+// keep theirs.
+fn randomize_looks(
+    p: &mut Particle,
+    rng: &mut StdRng,
+    num_of_colors: usize,
+    alpha_range: (f64, f64),
+    size_jitter: f64,
+) {
+    p.color_index =
+        rng.gen_range(0, num_of_colors.max(1));
+    // Either way around. When one is NaN,
+    // 'min' and 'max' both take the other,
+    // and when both are, fully opaque.
+    let (min, max) = alpha_range;
+    let (min, max) = (min.min(max), min.max(max));
+    let (min, max) = if min.is_nan() {
+        (1.0, 1.0)
+    } else {
+        (min.clamp(0.0, 1.0), max.clamp(0.0, 1.0))
+    };
+    p.alpha =
+        rng.sample(Uniform::new_inclusive(min, max));
+    let jitter = if size_jitter.is_nan() {
+        0.0
+    } else {
+        size_jitter.clamp(0.0, 1.0)
+    };
+    p.size_factor =
+        rng.sample(Uniform::new_inclusive(
+            1.0 - jitter,
+            1.0 + jitter,
+        ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simulation() -> Simulation {
+        let mut sim = Simulation::new(Some(1), 200);
+        sim.particle_size = 3.5;
+        sim.unit_size = 20.0;
+        sim.resize(400.0, 300.0);
+        sim
+    }
+
+    #[test]
+    fn set_speed_ignores_non_finite() {
+        let mut sim = simulation();
+        sim.set_speed(2.0);
+        sim.set_speed(f64::NAN);
+        assert_eq!(sim.speed, 2.0);
+        sim.set_speed(f64::INFINITY);
+        assert_eq!(sim.speed, 2.0);
+        sim.set_speed(100.0);
+        assert_eq!(sim.speed, MAX_SPEED);
+        sim.update(1.0 / 60.0);
+        assert!(sim
+            .particles
+            .iter()
+            .all(|p| p.x.is_finite()
+                && p.y.is_finite()));
+    }
+
+    #[test]
+    fn resize_to_invalid_sizes() {
+        let mut sim = simulation();
+        for &(w, h) in &[
+            (0.0, 0.0),
+            (-1.0, 100.0),
+            (f64::NAN, 100.0),
+            (100.0, f64::INFINITY),
+        ] {
+            sim.resize(w, h);
+            sim.update(1.0 / 60.0);
+            assert_eq!(sim.particles.len(), 200);
+            assert!(
+                sim.width >= 0.0 && sim.height >= 0.0
+            );
+            assert!(sim.width.is_finite());
+            assert!(sim.height.is_finite());
+        }
+    }
+
+    #[test]
+    fn particles_stay_within_margin() {
+        let mut sim = simulation();
+        sim.wrap_margin = Some(10.0);
+        for _ in 0..500 {
+            sim.update(1.0 / 60.0);
+        }
+        // One frame of movement at most beyond
+        // the margin before it wraps.
+        let max_step = sim.speed * sim.particle_size;
+        for p in &sim.particles {
+            assert!(p.x >= -10.0 - max_step);
+            assert!(p.x <= 400.0 + 10.0 + max_step);
+            assert!(p.y >= -10.0 - max_step);
+            assert!(p.y <= 300.0 + 10.0 + max_step);
+        }
+    }
+
+    #[test]
+    fn wraps_to_the_other_side() {
+        let mut sim = simulation();
+        sim.particles.truncate(1);
+        sim.particles[0].x = -11.0;
+        sim.particles[0].y = 150.0;
+        sim.wrap_margin = Some(10.0);
+        sim.set_speed(0.0);
+        sim.update(1.0 / 60.0);
+        assert_eq!(sim.particles[0].x, 410.0);
+    }
+
+    #[test]
+    fn no_nan_without_area() {
+        let mut sim = Simulation::new(Some(1), 50);
+        sim.resize(0.0, 0.0);
+        for _ in 0..10 {
+            sim.update(1.0 / 60.0);
+        }
+        assert!(sim.particles.iter().all(|p| {
+            p.x.is_finite() && p.y.is_finite()
+        }));
+    }
+
+    #[test]
+    fn non_finite_particles_are_reset() {
+        let mut sim = simulation();
+        sim.particles[0].x = f64::NAN;
+        sim.particles[1].y = f64::INFINITY;
+        sim.update(1.0 / 60.0);
+        assert!(sim.particles.iter().all(|p| {
+            p.x.is_finite() && p.y.is_finite()
+        }));
+    }
+
+    #[test]
+    fn particles_respawn_after_lifetime() {
+        let mut sim = Simulation::new(Some(1), 100);
+        sim.particle_lifetime = Some(30);
+        sim.resize(400.0, 300.0);
+
+        // Staggered.
+        let first_age = sim.particles[0].age;
+        assert!(sim
+            .particles
+            .iter()
+            .any(|p| p.age != first_age));
+        assert!(sim
+            .particles
+            .iter()
+            .all(|p| p.age < 30));
+
+        sim.set_speed(0.0);
+        sim.jitter = 0.0;
+        let before: Vec<(f64, f64)> = sim
+            .particles
+            .iter()
+            .map(|p| (p.x, p.y))
+            .collect();
+        for _ in 0..31 {
+            sim.update(1.0 / 60.0);
+        }
+        // Without speed, only respawning moves them.
+        for (p, (x, y)) in
+            sim.particles.iter().zip(before)
+        {
+            assert!(p.x != x || p.y != y);
+            assert!(p.age <= 30);
+        }
+    }
+
+    #[test]
+    fn fbm_with_one_octave_is_the_noise() {
+        let sim = simulation();
+        for &(x, y, z) in
+            &[(0.1, 0.2, 0.3), (1.5, 0.7, 2.0)]
+        {
+            assert_eq!(
+                sim.fbm(x, y, z),
+                sim.noise.get([x, y, z])
+            );
+        }
+    }
+
+    #[test]
+    fn fbm_stays_in_range() {
+        let mut sim = simulation();
+        sim.octaves = 4;
+        for i in 0..100 {
+            let v =
+                sim.fbm(i as f64 * 0.37, 0.5, 1.0);
+            assert!((-1.0..=1.0).contains(&v));
+        }
+    }
+
+    #[test]
+    fn reset_with_seed_reproduces_layout() {
+        let mut sim = simulation();
+        let positions =
+            |sim: &Simulation| -> Vec<(f64, f64)> {
+                sim.particles
+                    .iter()
+                    .map(|p| (p.x, p.y))
+                    .collect()
+            };
+        let initial = positions(&sim);
+        for _ in 0..10 {
+            sim.update(1.0 / 60.0);
+        }
+        assert_ne!(positions(&sim), initial);
+
+        sim.reset();
+        assert_eq!(sim.frame, 0);
+        assert_eq!(positions(&sim), initial);
+    }
+
+    #[test]
+    fn set_seed_keeps_particles() {
+        let mut sim = simulation();
+        for _ in 0..10 {
+            sim.update(1.0 / 60.0);
+        }
+        let x = sim.particles[0].x;
+        let before = sim.fbm(0.3, 0.3, 0.3);
+
+        sim.set_seed(2, false);
+        assert_eq!(sim.seed, Some(2));
+        assert_eq!(sim.frame, 10);
+        assert_eq!(sim.particles[0].x, x);
+        assert_ne!(sim.fbm(0.3, 0.3, 0.3), before);
+
+        sim.set_seed(2, true);
+        assert_eq!(sim.frame, 0);
+    }
+
+    #[test]
+    fn noise_time_advances_by_time_scale() {
+        let mut sim = simulation();
+        sim.time_scale = 2.0;
+        for _ in 0..50 {
+            sim.update(1.0 / 60.0);
+        }
+        assert!((sim.noise_time - 1.0).abs() < 1e-9);
+
+        sim.time_divisor = 50.0;
+        for _ in 0..50 {
+            sim.update(1.0 / 60.0);
+        }
+        assert!((sim.noise_time - 3.0).abs() < 1e-9);
+
+        sim.step_noise_time(-0.25);
+        assert!((sim.noise_time - 2.75).abs() < 1e-9);
+        sim.set_noise_time(3.0);
+        assert_eq!(sim.noise_time, 3.0);
+    }
+
+    #[test]
+    fn add_and_remove_particles() {
+        let mut sim = simulation();
+        let first =
+            (sim.particles[0].x, sim.particles[0].y);
+
+        sim.add_particles(50);
+        assert_eq!(sim.particles.len(), 250);
+        assert_eq!(sim.num_of_particles, 250);
+        assert_eq!(
+            (sim.particles[0].x, sim.particles[0].y),
+            first
+        );
+        assert!(sim.particles.iter().all(|p| {
+            (0.0..=400.0).contains(&p.x)
+                && (0.0..=300.0).contains(&p.y)
+        }));
+
+        sim.remove_particles(100);
+        assert_eq!(sim.particles.len(), 150);
+        sim.remove_particles(1000);
+        assert!(sim.particles.is_empty());
+        assert_eq!(sim.num_of_particles, 0);
+    }
+
+    #[test]
+    fn angles_follow_the_flow() {
+        let mut sim = simulation();
+        sim.jitter = 0.0;
+        let initial = sim.particles.clone();
+        sim.update(1.0 / 60.0);
+        let first = sim.particles.clone();
+        for _ in 0..30 {
+            sim.update(1.0 / 60.0);
+        }
+        // Not the random ones from spawning.
+        assert!(initial
+            .iter()
+            .zip(&first)
+            .any(|(a, b)| a.angle != b.angle));
+        assert!(first
+            .iter()
+            .zip(&sim.particles)
+            .all(|(a, b)| a.angle != b.angle));
+    }
+
+    #[test]
+    fn field_angle_matches_particles() {
+        let mut sim = simulation();
+        sim.jitter = 0.0;
+        sim.update(1.0 / 60.0);
+        // Sampled where it was before moving.
+        for p in &sim.particles {
+            let angle =
+                sim.field_angle(p.prev_x, p.prev_y);
+            assert!((p.angle - angle).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn frozen_particles_keep_positions() {
+        let mut sim = simulation();
+        sim.particle_lifetime = Some(1);
+        sim.freeze_particles = true;
+        let before = sim.particles.clone();
+        for _ in 0..10 {
+            sim.update(1.0 / 60.0);
+        }
+        assert_eq!(sim.frame, 10);
+        for (a, b) in
+            before.iter().zip(&sim.particles)
+        {
+            assert_eq!((a.x, a.y), (b.x, b.y));
+        }
+        assert!(before
+            .iter()
+            .zip(&sim.particles)
+            .any(|(a, b)| a.angle != b.angle));
+    }
+
+    #[test]
+    fn frozen_field_keeps_angles() {
+        let mut sim = simulation();
+        sim.update(1.0 / 60.0);
+        sim.freeze_field = true;
+        let before = sim.particles.clone();
+        let noise_time = sim.noise_time;
+        for _ in 0..10 {
+            sim.update(1.0 / 60.0);
+        }
+        assert_eq!(sim.noise_time, noise_time);
+        for (a, b) in
+            before.iter().zip(&sim.particles)
+        {
+            assert_eq!(a.angle, b.angle);
+        }
+        assert!(before
+            .iter()
+            .zip(&sim.particles)
+            .any(|(a, b)| (a.x, a.y) != (b.x, b.y)));
+    }
+
+    #[test]
+    fn color_indices_within_palette() {
+        let mut sim = Simulation::new(Some(1), 200);
+        sim.num_of_colors = 3;
+        sim.resize(400.0, 300.0);
+        sim.add_particles(10);
+        assert!(sim
+            .particles
+            .iter()
+            .all(|p| p.color_index < 3));
+        assert!(sim
+            .particles
+            .iter()
+            .any(|p| p.color_index == 2));
+    }
+
+    #[test]
+    fn alphas_within_range() {
+        let mut sim = Simulation::new(Some(1), 200);
+        sim.alpha_range = (0.8, 0.3);
+        sim.resize(400.0, 300.0);
+        assert!(sim
+            .particles
+            .iter()
+            .all(
+                |p| p.alpha >= 0.3 && p.alpha <= 0.8
+            ));
+    }
+
+    #[test]
+    fn size_factors_within_jitter() {
+        let mut sim = Simulation::new(Some(1), 200);
+        sim.size_jitter = 0.5;
+        sim.resize(400.0, 300.0);
+        assert!(sim.particles.iter().all(|p| {
+            p.size_factor >= 0.5
+                && p.size_factor <= 1.5
+        }));
+    }
+
+    #[test]
+    fn invalid_looks_do_not_panic() {
+        let mut sim = Simulation::new(Some(1), 50);
+        sim.alpha_range = (f64::NAN, f64::NAN);
+        sim.size_jitter = f64::NAN;
+        sim.resize(400.0, 300.0);
+        assert!(sim.particles.iter().all(|p| {
+            p.alpha == 1.0 && p.size_factor == 1.0
+        }));
+
+        sim.alpha_range = (f64::NAN, 0.4);
+        sim.size_jitter = f64::INFINITY;
+        sim.resize(300.0, 200.0);
+        assert!(sim.particles.iter().all(|p| {
+            p.alpha == 0.4
+                && p.size_factor >= 0.0
+                && p.size_factor <= 2.0
+        }));
+    }
+
+    fn lone_particle(x: f64, y: f64) -> Simulation {
+        let mut sim = simulation();
+        sim.set_speed(0.0);
+        sim.particles =
+            vec![Particle::new(x, y, 0.0)];
+        sim
+    }
+
+    #[test]
+    fn attractor_pulls_without_overshooting() {
+        let mut sim = lone_particle(100.0, 150.0);
+        sim.attractor = Some((200.0, 150.0, 1.0));
+        sim.update(1.0 / 60.0);
+        let x = sim.particles[0].x;
+        assert!(x > 100.0 && x <= 200.0);
+
+        let mut sim = lone_particle(199.99, 150.0);
+        sim.attractor = Some((200.0, 150.0, 100.0));
+        sim.update(1.0 / 60.0);
+        assert!(sim.particles[0].x <= 200.0 + 1e-9);
+    }
+
+    #[test]
+    fn repulsor_push_is_bounded() {
+        let mut sim = lone_particle(200.0, 150.01);
+        sim.attractor = Some((200.0, 150.0, -1.0));
+        sim.update(1.0 / 60.0);
+        let p = &sim.particles[0];
+        // At most 'strength' times what the flow
+        // moves it (which is 'particle_size').
+        assert!(p.y > 150.01);
+        assert!(p.y - 150.01 <= 3.5 + 1e-9);
+    }
+
+    #[test]
+    fn impulse_pushes_away() {
+        let mut sim = simulation();
+        sim.particles.truncate(2);
+        sim.particles[0].x = 210.0;
+        sim.particles[0].y = 150.0;
+        sim.particles[1].x = 200.0;
+        sim.particles[1].y = 230.0;
+        sim.impulse(200.0, 150.0, 1.0);
+        // Within one unit (20), by one unit.
+        assert!(
+            (sim.particles[0].x - 230.0).abs() < 1e-9
+        );
+        // At four units, by a quarter.
+        assert!(
+            (sim.particles[1].y - 235.0).abs() < 1e-9
+        );
+    }
+
+    #[test]
+    fn spawn_shapes() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let dist = |p: &Particle| {
+            (p.x - 200.0).hypot(p.y - 150.0)
+        };
+
+        let circle = generate_particles(
+            &mut rng,
+            400.0,
+            300.0,
+            200,
+            SpawnShape::Circle,
+        );
+        assert!(circle
+            .iter()
+            .all(|p| dist(p) <= 150.0 + 1e-9));
+
+        let ring = generate_particles(
+            &mut rng,
+            400.0,
+            300.0,
+            200,
+            SpawnShape::Ring,
+        );
+        assert!(ring.iter().all(|p| {
+            dist(p) >= 105.0 - 1e-9
+                && dist(p) <= 150.0 + 1e-9
+        }));
+    }
+
+    #[test]
+    fn masked_spawns_in_bright_pixels() {
+        let mut rng = StdRng::seed_from_u64(1);
+        // Only the right half is bright.
+        let mask = [0, 255, 0, 255];
+        let particles = generate_particles_masked(
+            &mut rng, 400.0, 300.0, 200, &mask, 2, 2,
+        );
+        assert_eq!(particles.len(), 200);
+        assert!(particles
+            .iter()
+            .all(|p| p.x >= 200.0));
+
+        // All dark is the same as no mask.
+        let particles = generate_particles_masked(
+            &mut rng, 400.0, 300.0, 200, &[0; 4], 2,
+            2,
+        );
+        assert!(particles
+            .iter()
+            .any(|p| p.x < 200.0));
+    }
+
+    #[test]
+    fn intro_burst_expands_from_center() {
+        let mut sim = Simulation::new(Some(1), 50);
+        sim.intro_burst = true;
+        sim.resize(400.0, 300.0);
+        assert!(sim.particles.iter().all(|p| (
+            p.x, p.y
+        ) == (
+            200.0, 150.0
+        )));
+        let targets = sim
+            .intro
+            .as_ref()
+            .unwrap()
+            .targets
+            .clone();
+
+        // Resizing in the middle starts over.
+        sim.update(1.0 / 60.0);
+        sim.resize(400.0, 300.0);
+        assert_eq!(
+            sim.intro.as_ref().unwrap().progress,
+            0
+        );
+        assert_ne!(
+            sim.intro.as_ref().unwrap().targets,
+            targets
+        );
+        let targets = sim
+            .intro
+            .as_ref()
+            .unwrap()
+            .targets
+            .clone();
+
+        for _ in 0..INTRO_BURST_FRAMES {
+            sim.update(1.0 / 60.0);
+        }
+        assert!(sim.intro.is_none());
+        for (p, &(x, y)) in
+            sim.particles.iter().zip(&targets)
+        {
+            assert!((p.x - x).abs() < 1e-9);
+            assert!((p.y - y).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn view_shifts_the_field() {
+        let mut sim = simulation();
+        let angle = sim.field_angle(100.0, 80.0);
+        sim.set_view((30.0, 20.0), 1.0);
+        assert!(
+            (sim.field_angle(130.0, 100.0) - angle)
+                .abs()
+                < 1e-9
+        );
+        // Magnified around the origin.
+        sim.set_view((0.0, 0.0), 2.0);
+        assert!(
+            (sim.field_angle(200.0, 160.0) - angle)
+                .abs()
+                < 1e-9
+        );
+    }
+
+    #[test]
+    fn soft_bounds_push_inward() {
+        assert_eq!(
+            soft_bounds_push(50.0, 100.0, 10.0),
+            0.0
+        );
+        assert_eq!(
+            soft_bounds_push(5.0, 100.0, 10.0),
+            0.5
+        );
+        assert_eq!(
+            soft_bounds_push(0.0, 100.0, 10.0),
+            1.0
+        );
+        assert_eq!(
+            soft_bounds_push(110.0, 100.0, 10.0),
+            -2.0
+        );
+    }
+
+    #[test]
+    fn soft_bounds_hold_against_wind() {
+        let mut sim = simulation();
+        sim.edge_behavior =
+            EdgeBehavior::SoftBounds {
+                margin: 20.0,
+                strength: 1.0,
+            };
+        sim.wind = (1.0, 0.0);
+        for _ in 0..500 {
+            sim.update(1.0 / 60.0);
+        }
+        // The flow adds up to one more
+        // margin to what the wind does.
+        assert!(sim
+            .particles
+            .iter()
+            .all(|p| p.x <= 400.0 + 20.0 + 1e-9));
+    }
+
+    #[test]
+    fn separation_pushes_apart() {
+        let particles = [
+            Particle::new(10.0, 10.0, 0.0),
+            Particle::new(14.0, 10.0, 0.0),
+            Particle::new(80.0, 80.0, 0.0),
+        ];
+        let pushes = separation_pushes(
+            &particles, 100.0, 100.0, 8.0, false,
+        );
+        assert_eq!(pushes[0], (-0.5, 0.0));
+        assert_eq!(pushes[1], (0.5, 0.0));
+        assert_eq!(pushes[2], (0.0, 0.0));
+    }
+
+    #[test]
+    fn separation_pushes_across_wrapped_edges() {
+        let particles = [
+            Particle::new(2.0, 50.0, 0.0),
+            Particle::new(98.0, 50.0, 0.0),
+        ];
+        let pushes = separation_pushes(
+            &particles, 100.0, 100.0, 8.0, false,
+        );
+        assert_eq!(pushes[0], (0.0, 0.0));
+        // Neighbors across the edge, so they get
+        // pushed away from it.
+        let pushes = separation_pushes(
+            &particles, 100.0, 100.0, 8.0, true,
+        );
+        assert_eq!(pushes[0], (0.5, 0.0));
+        assert_eq!(pushes[1], (-0.5, 0.0));
+    }
+
+    #[test]
+    fn bounce_keeps_particles_inside() {
+        let mut sim = simulation();
+        sim.edge_behavior = EdgeBehavior::Bounce;
+        for _ in 0..500 {
+            sim.update(1.0 / 60.0);
+            for p in &sim.particles {
+                assert!((0.0..=400.0).contains(&p.x));
+                assert!((0.0..=300.0).contains(&p.y));
+            }
+        }
+    }
+}
@@ -1,7 +1,11 @@
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
 use num::{Float, NumCast};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::f64::consts::PI;
+use std::fmt;
+use std::rc::Rc;
 use std::time::Duration;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::{JsCast, JsValue};
@@ -15,30 +19,109 @@ pub fn exit(message: &str) {
     std::process::abort();
 }
 
+/// Calls `func` once `duration` has passed
+/// since the last call (trailing edge).
+/// Only one timer is pending at a time. When it fires
+/// earlier than expected (because of newer calls),
+/// it schedules another one for the remaining time.
 pub fn debounce<F>(
-    mut func: F,
+    func: F,
     duration: Duration,
 ) -> impl FnMut()
 where
-    F: FnMut(),
+    F: FnMut() + 'static,
 {
-    let mut last_call_time = Instant::now();
+    let state = Rc::new(RefCell::new(
+        Debouncer::new(duration),
+    ));
+    let func = Rc::new(RefCell::new(func));
 
     move || {
-        let now = Instant::now();
-        let elapsed = now
-            .duration_since(last_call_time)
-            .as_millis() as f64;
-        let should_call =
-            elapsed >= duration.as_millis() as f64;
-
-        if should_call {
-            func();
-            last_call_time = now;
+        let pending = state.borrow().is_pending();
+        state.borrow_mut().call(Instant::now());
+        if !pending {
+            schedule_trailing_call(
+                Rc::clone(&state),
+                Rc::clone(&func),
+                duration,
+            );
         }
     }
 }
 
+fn schedule_trailing_call<F>(
+    state: Rc<RefCell<Debouncer>>,
+    func: Rc<RefCell<F>>,
+    delay: Duration,
+) where
+    F: FnMut() + 'static,
+{
+    let callback = Closure::once_into_js(move || {
+        let remaining =
+            state.borrow().remaining(Instant::now());
+        if remaining.is_zero() {
+            state.borrow_mut().reset();
+            (func.borrow_mut())();
+        } else {
+            schedule_trailing_call(
+                state, func, remaining,
+            );
+        }
+    });
+    get_window()
+        .expect("No window")
+        .set_timeout_with_callback_and_timeout_and_arguments_0(
+            callback.unchecked_ref(),
+            delay.as_millis() as i32,
+        )
+        .expect("Failed to set timeout");
+}
+
+/// Keeps track of calls for `debounce`.
+pub struct Debouncer {
+    duration: Duration,
+    last_call_time: Option<Instant>,
+}
+
+impl Debouncer {
+    pub fn new(duration: Duration) -> Self {
+        Debouncer {
+            duration,
+            last_call_time: None,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.last_call_time.is_some()
+    }
+
+    pub fn call(&mut self, now: Instant) {
+        self.last_call_time = Some(now);
+    }
+
+    pub fn reset(&mut self) {
+        self.last_call_time = None;
+    }
+
+    /// Time left until the trailing call.
+    /// Zero means it is time to call.
+    pub fn remaining(
+        &self,
+        now: Instant,
+    ) -> Duration {
+        self.last_call_time.map_or(
+            Duration::ZERO,
+            |last_call_time| {
+                self.duration.saturating_sub(
+                    now.duration_since(
+                        last_call_time,
+                    ),
+                )
+            },
+        )
+    }
+}
+
 pub async fn timer(msec: i32) -> Result<(), JsValue> {
     let promise = js_sys::Promise::new(
         &mut |resolve, _| {
@@ -56,17 +139,57 @@ pub async fn timer(msec: i32) -> Result<(), JsValue> {
     Ok(())
 }
 
-pub fn get_window() -> Result<web_sys::Window, String>
-{
-    web_sys::window()
-        .ok_or_else(|| "No window".into())
+/// Errors from looking up (or creating)
+/// DOM objects. Converts into `JsValue`
+/// so that it can be thrown to JS.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DomError {
+    NoWindow,
+    NoDocument,
+    ElementNotFound(String),
+    CastFailed(String),
+}
+
+impl fmt::Display for DomError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self {
+            DomError::NoWindow => {
+                write!(f, "No window")
+            }
+            DomError::NoDocument => {
+                write!(f, "No document")
+            }
+            DomError::ElementNotFound(name) => {
+                write!(f, "No element: {}", name)
+            }
+            DomError::CastFailed(name) => {
+                write!(f, "Failed to get {}", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DomError {}
+
+impl From<DomError> for JsValue {
+    fn from(err: DomError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
+
+pub fn get_window(
+) -> Result<web_sys::Window, DomError> {
+    web_sys::window().ok_or(DomError::NoWindow)
 }
 
 pub fn get_document(
-) -> Result<web_sys::Document, String> {
+) -> Result<web_sys::Document, DomError> {
     get_window()?
         .document()
-        .ok_or_else(|| "No document".into())
+        .ok_or(DomError::NoDocument)
 }
 
 pub fn device_pixel_ratio() -> f64 {
@@ -123,41 +246,70 @@ pub fn request_animation_frame_future(
 
 pub fn get_wrapper_element(
     name: &str,
-) -> Result<web_sys::HtmlElement, String> {
-    let elem: web_sys::Element = get_document()?
+) -> Result<web_sys::HtmlElement, DomError> {
+    get_document()?
         .get_element_by_id(name)
-        .ok_or(format!("No element: {}", name))?;
-
-    Ok(elem
+        .ok_or_else(|| {
+            DomError::ElementNotFound(name.into())
+        })?
         .dyn_into::<web_sys::HtmlElement>()
-        .map_err(|e| e.to_string())?)
+        .map_err(|_| {
+            DomError::CastFailed(name.into())
+        })
 }
 
+// Invalid selectors are not found either.
 pub fn get_canvas(
     id: &str,
-) -> Result<web_sys::HtmlCanvasElement, String> {
-    let canvas = get_document()?
+) -> Result<web_sys::HtmlCanvasElement, DomError> {
+    get_document()?
         .query_selector(id)
-        .map_err(|_| format!("No element: {}", id))?
-        .unwrap()
+        .ok()
+        .flatten()
+        .ok_or_else(|| {
+            DomError::ElementNotFound(id.into())
+        })?
         .dyn_into::<web_sys::HtmlCanvasElement>()
         .map_err(|_| {
-            "Failed to get canvas".to_string()
-        })?;
-    Ok(canvas)
+            DomError::CastFailed(format!(
+                "canvas {}",
+                id
+            ))
+        })
+}
+
+/// Creates a canvas element which is not
+/// attached to the document.
+pub fn create_canvas(
+) -> Result<web_sys::HtmlCanvasElement, DomError> {
+    get_document()?
+        .create_element("canvas")
+        .ok()
+        .and_then(|el| {
+            el.dyn_into::<web_sys::HtmlCanvasElement>(
+            )
+            .ok()
+        })
+        .ok_or_else(|| {
+            DomError::CastFailed("canvas".into())
+        })
 }
 
 pub fn get_ctx(
     canvas: &web_sys::HtmlCanvasElement,
-) -> Result<web_sys::CanvasRenderingContext2d, String>
+) -> Result<web_sys::CanvasRenderingContext2d, DomError>
 {
-    let ctx = canvas
+    canvas
         .get_context("2d")
-        .map_err(|_| "Failed get 2D Context".to_string())?
-        .unwrap()
-        .dyn_into::<web_sys::CanvasRenderingContext2d>()
-        .unwrap();
-    Ok(ctx)
+        .ok()
+        .flatten()
+        .and_then(|ctx| {
+            ctx.dyn_into::<web_sys::CanvasRenderingContext2d>()
+                .ok()
+        })
+        .ok_or_else(|| {
+            DomError::CastFailed("2D context".into())
+        })
 }
 
 pub fn get_canvas_size(
@@ -204,6 +356,100 @@ pub fn lerp(norm: f64, min: f64, max: f64) -> f64 {
     min + (max - min) * norm
 }
 
+/// 2D version of `lerp`.
+/// Ex. lerp_point(0.5, (0, 0), (10, 20)) ---> (5, 10)
+pub fn lerp_point(
+    t: f64,
+    a: (f64, f64),
+    b: (f64, f64),
+) -> (f64, f64) {
+    (lerp(t, a.0, b.0), lerp(t, a.1, b.1))
+}
+
+/// "transparent" (or an empty string)
+/// for 'bgcolor'. Not a hex code.
+pub fn is_transparent(color: &str) -> bool {
+    let color = color.trim();
+    color.is_empty()
+        || color.eq_ignore_ascii_case("transparent")
+}
+
+/// Interpolates angles (radians) along
+/// the shorter arc so that, ex. 0.1 and 6.2
+/// are averaged to about 0 (not 3.15).
+/// The result is not normalized to [0, 2PI).
+pub fn lerp_angle(a: f64, b: f64, t: f64) -> f64 {
+    a + angle_diff(a, b) * t
+}
+
+/// From 'a' to 'b' along the shorter arc
+/// (from -PI to PI).
+pub fn angle_diff(a: f64, b: f64) -> f64 {
+    (b - a + PI).rem_euclid(2.0 * PI) - PI
+}
+
+/// Euclidean distance between 2 points.
+pub fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (b.0 - a.0).hypot(b.1 - a.1)
+}
+
+/// Same as 'distance', but on a plane whose
+/// edges are connected (as particles wrap),
+/// so that ex. x=1 and x=w-1 are 2 apart.
+pub fn toroidal_distance(
+    ax: f64,
+    ay: f64,
+    bx: f64,
+    by: f64,
+    w: f64,
+    h: f64,
+) -> f64 {
+    let (dx, dy) =
+        toroidal_delta(ax, ay, bx, by, w, h);
+    dx.hypot(dy)
+}
+
+/// From 'a' to 'b' the shorter way around
+/// when the edges are connected (so, each
+/// is from -len/2 to len/2).
+pub fn toroidal_delta(
+    ax: f64,
+    ay: f64,
+    bx: f64,
+    by: f64,
+    w: f64,
+    h: f64,
+) -> (f64, f64) {
+    let across = |d: f64, len: f64| {
+        if len > 0.0 {
+            (d + len / 2.0).rem_euclid(len)
+                - len / 2.0
+        } else {
+            d
+        }
+    };
+    (across(bx - ax, w), across(by - ay, h))
+}
+
+/// From CSS pixels (ex. mouse positions)
+/// to device pixels (which particles use).
+pub fn screen_to_canvas(
+    x: f64,
+    y: f64,
+    dpr: f64,
+) -> (f64, f64) {
+    (x * dpr, y * dpr)
+}
+
+/// Inverse of `screen_to_canvas`.
+pub fn canvas_to_screen(
+    x: f64,
+    y: f64,
+    dpr: f64,
+) -> (f64, f64) {
+    (x / dpr, y / dpr)
+}
+
 pub fn f64_from_js(js: JsValue) -> f64 {
     js.as_f64().unwrap_or_default()
 }
@@ -223,6 +469,47 @@ pub fn ease_in_out_quad(v: f64) -> f64 {
     }
 }
 
+// For anything going from one value to
+// another over frames (fade-in, color
+// transitions, speed ramps).
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    Linear,
+    InOutQuad,
+    InQuad,
+    OutQuad,
+    InOutCubic,
+}
+
+impl Easing {
+    // 't' is from 0.0 to 1.0 (clamped).
+    pub fn apply(self, t: f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::InOutQuad => ease_in_out_quad(t),
+            Easing::InQuad => t * t,
+            Easing::OutQuad => t * (2.0 - t),
+            Easing::InOutCubic => {
+                if t < 0.5 {
+                    4.0 * t.powi(3)
+                } else {
+                    1.0 - (2.0 - t * 2.0).powi(3)
+                        / 2.0
+                }
+            }
+        }
+    }
+}
+
 pub struct RgbColor {
     pub r: u8,
     pub g: u8,
@@ -236,18 +523,160 @@ pub fn rgb_to_hex(rgb_color: &RgbColor) -> String {
     )
 }
 
-pub fn hex_to_rgb(hex_color: &str) -> RgbColor {
-    let hex_value = hex::decode(
-        hex_color.trim_start_matches('#'),
-    )
-    .expect("Invalid hex code");
+/// Accepts 3, 4, 6, and 8 digits.
+/// Shorthands are expanded (Ex. "#fa0" ---> "#ffaa00"),
+/// and alpha (for 4 and 8 digits) is ignored.
+pub fn hex_to_rgb(
+    hex_color: &str,
+) -> Result<RgbColor, String> {
+    let digits = hex_color.trim_start_matches('#');
+    let expanded: String;
+    let digits = match digits.len() {
+        3 | 4 => {
+            expanded = digits
+                .chars()
+                .flat_map(|c| [c, c])
+                .collect();
+            &expanded
+        }
+        _ => digits,
+    };
+    let invalid =
+        || format!("Invalid hex code: {}", hex_color);
+    let hex_value =
+        hex::decode(digits).map_err(|_| invalid())?;
     match hex_value.as_slice() {
-        [r, g, b] => RgbColor {
+        [r, g, b] | [r, g, b, _] => Ok(RgbColor {
             r: *r,
             g: *g,
             b: *b,
-        },
-        _ => panic!("Invalid hex code"),
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+/// Ex. hex_to_rgba_string("#ff0000", 0.5)
+/// ---> "rgba(255, 0, 0, 0.5)"
+pub fn hex_to_rgba_string(
+    hex_color: &str,
+    alpha: f64,
+) -> Result<String, String> {
+    let rgb = hex_to_rgb(hex_color)?;
+    Ok(format!(
+        "rgba({}, {}, {}, {})",
+        rgb.r, rgb.g, rgb.b, alpha
+    ))
+}
+
+/// `h` in degrees (0 to 360), and
+/// `s` and `l` between 0 and 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HslColor {
+    pub h: f64,
+    pub s: f64,
+    pub l: f64,
+}
+
+pub fn rgb_to_hsl(rgb_color: &RgbColor) -> HslColor {
+    let r = rgb_color.r as f64 / 255.0;
+    let g = rgb_color.g as f64 / 255.0;
+    let b = rgb_color.b as f64 / 255.0;
+
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+    let d = max - min;
+
+    if d == 0.0 {
+        return HslColor { h: 0.0, s: 0.0, l };
+    }
+
+    let s = d / (1.0 - (2.0 * l - 1.0).abs());
+    let h = if max == r {
+        60.0 * ((g - b) / d)
+    } else if max == g {
+        60.0 * ((b - r) / d + 2.0)
+    } else {
+        60.0 * ((r - g) / d + 4.0)
+    };
+
+    HslColor {
+        h: h.rem_euclid(360.0),
+        s,
+        l,
+    }
+}
+
+/// Hue wraps around, so that -30 and 330
+/// give the same color.
+pub fn hsl_to_rgb(hsl_color: &HslColor) -> RgbColor {
+    let h = hsl_color.h.rem_euclid(360.0);
+    let s = hsl_color.s.clamp(0.0, 1.0);
+    let l = hsl_color.l.clamp(0.0, 1.0);
+
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x =
+        c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+    let m = l - c / 2.0;
+
+    let (r, g, b) = match (h / 60.0) as u8 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    let to_u8 = |v: f64| {
+        ((v + m) * 255.0).round().clamp(0.0, 255.0)
+            as u8
+    };
+
+    RgbColor {
+        r: to_u8(r),
+        g: to_u8(g),
+        b: to_u8(b),
+    }
+}
+
+pub fn hsl_to_hex(hsl_color: &HslColor) -> String {
+    rgb_to_hex(&hsl_to_rgb(hsl_color))
+}
+
+/// Same as 'lerp_rgb', but for hex codes.
+/// When either is not a valid hex code (ex. CSS
+/// color names), it jumps to 'to' at the end.
+pub fn lerp_hex(
+    norm: f64,
+    from: &str,
+    to: &str,
+) -> String {
+    match (hex_to_rgb(from), hex_to_rgb(to)) {
+        (Ok(a), Ok(b)) => {
+            rgb_to_hex(&lerp_rgb(norm, &a, &b))
+        }
+        _ if norm < 1.0 => from.to_string(),
+        _ => to.to_string(),
+    }
+}
+
+/// Linear interpolation between 2 colors.
+/// Ex. lerp_rgb(0.5, black, white) ---> gray
+pub fn lerp_rgb(
+    norm: f64,
+    from: &RgbColor,
+    to: &RgbColor,
+) -> RgbColor {
+    let channel = |a: u8, b: u8| {
+        lerp(norm, a as f64, b as f64)
+            .round()
+            .clamp(0.0, 255.0) as u8
+    };
+    RgbColor {
+        r: channel(from.r, to.r),
+        g: channel(from.g, to.g),
+        b: channel(from.b, to.b),
     }
 }
 
@@ -256,23 +685,386 @@ pub fn color_change_intensity_rgb(
     intensity: f64,
 ) -> RgbColor {
     let r = ((rbg.r as f64 * intensity) as i16)
-        .max(0)
-        .min(255) as u8;
+        .clamp(0, 255) as u8;
     let g = ((rbg.g as f64 * intensity) as i16)
-        .max(0)
-        .min(255) as u8;
+        .clamp(0, 255) as u8;
     let b = ((rbg.b as f64 * intensity) as i16)
-        .max(0)
-        .min(255) as u8;
+        .clamp(0, 255) as u8;
     RgbColor { r, g, b }
 }
 
 pub fn color_change_intensity_hex(
     hex_color: &str,
     intensity: f64,
-) -> String {
-    let rgb = hex_to_rgb(hex_color);
+) -> Result<String, String> {
+    let rgb = hex_to_rgb(hex_color)?;
     let new_rgb =
         color_change_intensity_rgb(&rgb, intensity);
-    rgb_to_hex(&new_rgb)
+    Ok(rgb_to_hex(&new_rgb))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dom_error_messages() {
+        assert_eq!(
+            DomError::ElementNotFound("#foo".into())
+                .to_string(),
+            "No element: #foo"
+        );
+        assert_eq!(
+            DomError::CastFailed("2D context".into())
+                .to_string(),
+            "Failed to get 2D context"
+        );
+    }
+
+    #[test]
+    fn easings_are_monotonic() {
+        for easing in [
+            Easing::Linear,
+            Easing::InOutQuad,
+            Easing::InQuad,
+            Easing::OutQuad,
+            Easing::InOutCubic,
+        ] {
+            assert_eq!(easing.apply(0.0), 0.0);
+            assert!(
+                (easing.apply(1.0) - 1.0).abs()
+                    < 1e-9
+            );
+            let values: Vec<f64> = (0..=100)
+                .map(|i| {
+                    easing.apply(i as f64 / 100.0)
+                })
+                .collect();
+            assert!(values
+                .windows(2)
+                .all(|w| w[0] <= w[1]));
+            // Clamped outside.
+            assert_eq!(easing.apply(-1.0), 0.0);
+            assert_eq!(
+                easing.apply(2.0),
+                easing.apply(1.0)
+            );
+        }
+    }
+
+    #[test]
+    fn debouncer_waits_for_quiet_period() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(ms(500));
+
+        assert!(!debouncer.is_pending());
+
+        // Rapid calls every 100 msec.
+        for i in 0..5 {
+            debouncer.call(start + ms(i * 100));
+        }
+        assert!(debouncer.is_pending());
+
+        // The first timer fires too early.
+        assert_eq!(
+            debouncer.remaining(start + ms(500)),
+            ms(400)
+        );
+
+        // Quiet for 500 msec since the last call.
+        assert!(debouncer
+            .remaining(start + ms(900))
+            .is_zero());
+
+        debouncer.reset();
+        assert!(!debouncer.is_pending());
+    }
+
+    #[test]
+    fn hex_to_rgb_lengths() {
+        for hex in
+            ["#fa0", "#fa08", "#ffaa00", "#ffaa0080"]
+        {
+            let rgb = hex_to_rgb(hex).unwrap();
+            assert_eq!(
+                (rgb.r, rgb.g, rgb.b),
+                (255, 170, 0),
+                "{}",
+                hex
+            );
+        }
+        // '#' is optional.
+        assert!(hex_to_rgb("ffaa00").is_ok());
+    }
+
+    #[test]
+    fn hex_to_rgb_invalid() {
+        for hex in [
+            "", "#f", "#ffaa0", "#ffaa000", "#gggggg",
+        ] {
+            assert!(
+                hex_to_rgb(hex).is_err(),
+                "{}",
+                hex
+            );
+        }
+    }
+
+    #[test]
+    fn point_helpers() {
+        assert_eq!(
+            lerp_point(0.5, (0.0, 0.0), (10.0, 20.0)),
+            (5.0, 10.0)
+        );
+        assert_eq!(
+            lerp_point(0.0, (1.0, 2.0), (10.0, 20.0)),
+            (1.0, 2.0)
+        );
+        assert_eq!(
+            distance((0.0, 0.0), (3.0, 4.0)),
+            5.0
+        );
+        assert_eq!(
+            distance((3.0, 4.0), (0.0, 0.0)),
+            5.0
+        );
+    }
+
+    #[test]
+    fn lerp_angle_takes_shorter_arc() {
+        let close =
+            |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+        assert!(close(
+            lerp_angle(1.0, 2.0, 0.5),
+            1.5
+        ));
+        assert!(close(
+            lerp_angle(2.0, 1.0, 0.25),
+            1.75
+        ));
+
+        // Across 0/2PI.
+        let mid = lerp_angle(0.1, 6.2, 0.5);
+        assert!(close(
+            mid,
+            (0.1 + 6.2 - 2.0 * PI) / 2.0
+        ));
+        let mid = lerp_angle(6.2, 0.1, 0.5);
+        assert!(close(
+            mid,
+            (6.2 + 0.1 + 2.0 * PI) / 2.0
+        ));
+
+        // Ends.
+        assert!(close(
+            lerp_angle(0.1, 6.2, 0.0),
+            0.1
+        ));
+        assert!(close(
+            lerp_angle(0.1, 6.2, 1.0),
+            6.2 - 2.0 * PI
+        ));
+    }
+
+    #[test]
+    fn toroidal_distance_across_edges() {
+        let close =
+            |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(
+            toroidal_distance(
+                1.0, 50.0, 99.0, 50.0, 100.0, 100.0
+            ),
+            2.0
+        ));
+        // Same as 'distance' when closer inside.
+        assert!(close(
+            toroidal_distance(
+                10.0, 10.0, 13.0, 14.0, 100.0, 100.0
+            ),
+            5.0
+        ));
+        assert!(close(
+            toroidal_distance(
+                1.0, 1.0, 99.0, 99.0, 100.0, 100.0
+            ),
+            8_f64.sqrt()
+        ));
+    }
+
+    #[test]
+    fn toroidal_delta_shorter_way() {
+        let close =
+            |a: f64, b: f64| (a - b).abs() < 1e-9;
+        let (dx, dy) = toroidal_delta(
+            1.0, 50.0, 99.0, 53.0, 100.0, 100.0,
+        );
+        assert!(close(dx, -2.0));
+        assert!(close(dy, 3.0));
+        let (dx, dy) = toroidal_delta(
+            99.0, 2.0, 1.0, 98.0, 100.0, 100.0,
+        );
+        assert!(close(dx, 2.0));
+        assert!(close(dy, -4.0));
+    }
+
+    #[test]
+    fn angle_diff_within_half_turn() {
+        let close =
+            |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(angle_diff(1.0, 2.0), 1.0));
+        assert!(close(angle_diff(2.0, 1.0), -1.0));
+        assert!(close(
+            angle_diff(6.2, 0.1),
+            0.1 + 2.0 * PI - 6.2
+        ));
+    }
+
+    #[test]
+    fn lerp_hex_between_colors() {
+        assert_eq!(
+            lerp_hex(0.0, "#000000", "#ffffff"),
+            "#000000"
+        );
+        assert_eq!(
+            lerp_hex(1.0, "#000000", "#ffffff"),
+            "#ffffff"
+        );
+        assert_eq!(
+            lerp_hex(0.5, "#000000", "#ff0000"),
+            "#800000"
+        );
+        // Not hex codes.
+        assert_eq!(
+            lerp_hex(0.5, "red", "#000"),
+            "red"
+        );
+        assert_eq!(
+            lerp_hex(1.0, "red", "#000"),
+            "#000"
+        );
+    }
+
+    #[test]
+    fn screen_canvas_round_trip() {
+        for dpr in [1.0, 1.5, 2.0, 3.0] {
+            let (cx, cy) =
+                screen_to_canvas(120.0, 45.5, dpr);
+            assert_eq!(
+                (cx, cy),
+                (120.0 * dpr, 45.5 * dpr)
+            );
+            let (sx, sy) =
+                canvas_to_screen(cx, cy, dpr);
+            assert!((sx - 120.0).abs() < 1e-9);
+            assert!((sy - 45.5).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn color_intensity_halves() {
+        let rgb = RgbColor {
+            r: 200,
+            g: 100,
+            b: 50,
+        };
+        let half =
+            color_change_intensity_rgb(&rgb, 0.5);
+        assert_eq!(
+            (half.r, half.g, half.b),
+            (100, 50, 25)
+        );
+    }
+
+    #[test]
+    fn color_intensity_clamps_to_255() {
+        let rgb = RgbColor {
+            r: 200,
+            g: 100,
+            b: 0,
+        };
+        let double =
+            color_change_intensity_rgb(&rgb, 2.0);
+        assert_eq!(
+            (double.r, double.g, double.b),
+            (255, 200, 0)
+        );
+        assert_eq!(
+            color_change_intensity_hex(
+                "#ffffff", 100.0
+            )
+            .unwrap(),
+            "#ffffff"
+        );
+    }
+
+    #[test]
+    fn color_intensity_zero_is_black() {
+        assert_eq!(
+            color_change_intensity_hex(
+                "#c0ffee", 0.0
+            )
+            .unwrap(),
+            "#000000"
+        );
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        for hex in [
+            "#000000", "#ffffff", "#c0ffee",
+            "#202020",
+        ] {
+            assert_eq!(
+                rgb_to_hex(&hex_to_rgb(hex).unwrap()),
+                hex
+            );
+        }
+    }
+
+    #[test]
+    fn hsl_round_trip() {
+        for hex in [
+            "#000000", "#ffffff", "#ff0000",
+            "#00ff00", "#0000ff", "#202020",
+            "#ff8800", "#7f3fbf", "#12abef",
+        ] {
+            let rgb = hex_to_rgb(hex).unwrap();
+            let back = hsl_to_rgb(&rgb_to_hsl(&rgb));
+            for (a, b) in [
+                (rgb.r, back.r),
+                (rgb.g, back.g),
+                (rgb.b, back.b),
+            ] {
+                assert!(
+                    (a as i16 - b as i16).abs() <= 1,
+                    "{} came back as {}",
+                    hex,
+                    rgb_to_hex(&back)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn hsl_hue_wraps_around() {
+        let red = HslColor {
+            h: 0.0,
+            s: 1.0,
+            l: 0.5,
+        };
+        assert_eq!(hsl_to_hex(&red), "#ff0000");
+        assert_eq!(
+            hsl_to_hex(&HslColor { h: 360.0, ..red }),
+            "#ff0000"
+        );
+        assert_eq!(
+            hsl_to_hex(&HslColor {
+                h: -240.0,
+                ..red
+            }),
+            hsl_to_hex(&HslColor { h: 120.0, ..red })
+        );
+    }
 }